@@ -1,43 +1,192 @@
-use crate::handlers::bambu::{BambuClient, BambuDevice, BambuMQTTClient};
+use crate::commands::camera::CAMERA_MANAGER;
+use crate::handlers::ams::{suggest_mapping, AmsTray, ProjectFilament};
+use crate::handlers::bambu::{
+    match_announcements, BambuApiError, BambuClient, BambuCloudMqttCredentials, BambuDevice,
+    BambuMQTTClient,
+};
+use crate::handlers::config::{get_config_path, update_config, Config};
+use crate::handlers::discovery::{
+    load_discovery_settings, run_discovery_service, DeviceIpChange, DiscoveredDevices,
+};
+use crate::handlers::firmware::{
+    build_report_entry, parse_module_versions, ModuleVersion, UpgradeProgressEvent, UpgradeState,
+};
+use crate::handlers::history::TaskHistoryCache;
+use crate::handlers::snapshots::run_snapshot_worker;
+use crate::handlers::sync::{forget_device, run_device_sync, DeviceChange};
+use crate::handlers::timelapse::run_timelapse_worker;
 use lazy_static::lazy_static;
 use serde_json::json;
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::Manager;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::Mutex;
 
+// Page size used when the frontend doesn't ask for one
+const DEFAULT_TASK_PAGE_SIZE: u32 = 20;
+
+// How long to wait for printers to answer info.get_version
+const VERSION_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often the background task checks whether the jwt needs refreshing
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
 lazy_static! {
-    static ref BAMBU_CLIENT: BambuClient = BambuClient::new();
+    pub(crate) static ref BAMBU_CLIENT: BambuClient = BambuClient::new();
     static ref BAMBU_MQTT_CLIENT: Mutex<BambuMQTTClient> = Mutex::new(BambuMQTTClient::new());
+    static ref SESSION_REFRESHER: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+    static ref DEVICE_SYNC: Mutex<Option<tokio::task::JoinHandle<()>>> = Mutex::new(None);
+    static ref EVENT_WORKERS: Mutex<Vec<tokio::task::JoinHandle<()>>> = Mutex::new(vec![]);
+    static ref DISCOVERED_DEVICES: DiscoveredDevices = DiscoveredDevices::default();
+    // Kept apart from the other workers with where it reports moves, so new settings can restart it
+    static ref DISCOVERY_SERVICE: Mutex<Option<(tokio::task::JoinHandle<()>, UnboundedSender<DeviceIpChange>)>> =
+        Mutex::new(None);
 }
 
 #[tauri::command]
-pub async fn login_to_bambu(username: String, password: String) -> Result<String, String> {
+pub async fn login_to_bambu(
+    username: String,
+    password: String,
+    request_id: Option<String>,
+) -> Result<String, BambuApiError> {
     println!("[commands::bambu::login_to_bambu] trying to login to bambu with username: {} and password: {}", username, password);
 
     let client = BAMBU_CLIENT.borrow();
-    let response = client.login(&username, &password).await;
+    let cancel = client.track_request(request_id);
+    let response = client.login(&username, &password, cancel.as_ref()).await;
 
     println!("[commands::bambu::login_to_bambu] response: {:?}", response);
     match response {
         Ok(response) => {
             // Serialize the response to JSON
-            let serialized_response =
-                serde_json::to_string(&response).map_err(|e| e.to_string())?;
+            let serialized_response = serde_json::to_string(&response)
+                .map_err(|e| BambuApiError::Other(e.to_string()))?;
             Ok(serialized_response)
         }
-        Err(e) => Err(e.to_string()),
+        Err(e) => Err(e),
+    }
+}
+
+#[tauri::command]
+pub async fn request_login_code(request_id: Option<String>) -> Result<String, BambuApiError> {
+    println!("[commands::bambu::request_login_code] requesting login code");
+
+    let client = BAMBU_CLIENT.borrow();
+    let cancel = client.track_request(request_id);
+    client.request_login_code(cancel.as_ref()).await?;
+    Ok("".to_string())
+}
+
+#[tauri::command]
+pub async fn submit_login_code(
+    code: String,
+    request_id: Option<String>,
+) -> Result<String, BambuApiError> {
+    println!("[commands::bambu::submit_login_code] submitting login code");
+
+    let client = BAMBU_CLIENT.borrow();
+    let cancel = client.track_request(request_id);
+    let response = client.submit_login_code(&code, cancel.as_ref()).await;
+
+    println!(
+        "[commands::bambu::submit_login_code] response: {:?}",
+        response
+    );
+    match response {
+        Ok(response) => {
+            let serialized_response = serde_json::to_string(&response)
+                .map_err(|e| BambuApiError::Other(e.to_string()))?;
+            Ok(serialized_response)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Tell the frontend to send the user back to sign in when the session can't be refreshed
+fn emit_if_session_expired(app_handle: &tauri::AppHandle, error: &BambuApiError) {
+    if error.is_unauthorized() {
+        let _ = app_handle.emit_all("session-expired", error);
     }
 }
 
 #[tauri::command]
-pub async fn set_jwt(jwt: String) -> Result<String, String> {
+pub async fn get_cloud_endpoints() -> Result<String, String> {
+    let endpoints = BAMBU_CLIENT.endpoints();
+    serde_json::to_string(&endpoints).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn set_jwt(app_handle: tauri::AppHandle, jwt: String) -> Result<String, String> {
     println!("[commands::bambu::set_jwt] setting jwt: {}", jwt);
 
     let client = BAMBU_CLIENT.borrow();
     client.set_jwt(jwt).await;
 
+    // Keep the session alive in the background for as long as the app is open
+    let mut refresher = SESSION_REFRESHER.lock().await;
+    if let Some(handle) = refresher.take() {
+        handle.abort();
+    }
+
+    // Pick up renamed, newly bound and removed printers while the app is open
+    let mut device_sync = DEVICE_SYNC.lock().await;
+    if let Some(handle) = device_sync.take() {
+        handle.abort();
+    }
+    *device_sync = Some(tokio::spawn(run_device_sync(
+        app_handle.clone(),
+        BAMBU_CLIENT.borrow(),
+    )));
+
+    *refresher = Some(tokio::spawn(async move {
+        loop {
+            if let Err(e) = BAMBU_CLIENT.ensure_valid_jwt().await {
+                println!(
+                    "[commands::bambu::task::session_refresher] failed to refresh session: {}",
+                    e
+                );
+
+                if e.is_unauthorized() {
+                    emit_if_session_expired(&app_handle, &e);
+                    break;
+                }
+            }
+
+            tokio::time::sleep(SESSION_CHECK_INTERVAL).await;
+        }
+    }));
+
     Ok("".to_string())
 }
 
+// Called by the frontend when it no longer needs an answer, e.g. the user left the page.
+// Only the call started with this request_id is cancelled
+#[tauri::command]
+pub async fn cancel_cloud_request(request_id: String) -> Result<bool, String> {
+    println!(
+        "[commands::bambu::cancel_cloud_request] cancelling cloud request: {}",
+        request_id
+    );
+
+    Ok(BAMBU_CLIENT.cancel_request(&request_id))
+}
+
+#[tauri::command]
+pub async fn refresh_session(app_handle: tauri::AppHandle) -> Result<String, BambuApiError> {
+    println!("[commands::bambu::refresh_session] refreshing session");
+
+    let client = BAMBU_CLIENT.borrow();
+    match client.refresh_jwt().await {
+        Ok(_) => Ok(client.get_jwt().await.unwrap_or_default()),
+        Err(e) => {
+            emit_if_session_expired(&app_handle, &e);
+            Err(e)
+        }
+    }
+}
+
 #[tauri::command]
 pub async fn get_jwt() -> Result<String, String> {
     println!("[commands::bambu::get_jwt] getting jwt");
@@ -53,35 +202,244 @@ pub async fn get_jwt() -> Result<String, String> {
 }
 
 #[tauri::command]
-pub async fn fetch_devices() -> Result<String, String> {
+pub async fn fetch_devices(
+    app_handle: tauri::AppHandle,
+    request_id: Option<String>,
+) -> Result<String, BambuApiError> {
     println!("[commands::bambu::fetch_devices] fetching devices");
 
     let client = BAMBU_CLIENT.borrow();
-    let devices = client.get_devices().await;
+    let cancel = client.track_request(request_id);
+    let devices = client.get_devices(cancel.as_ref()).await;
     println!("[commands::bambu::fetch_devices] devices: {:?}", devices);
 
     match devices {
         Ok(devices) => {
             // Serialize the response to JSON
-            let serialized_devices = serde_json::to_string(&devices).map_err(|e| e.to_string())?;
+            let serialized_devices =
+                serde_json::to_string(&devices).map_err(|e| BambuApiError::Other(e.to_string()))?;
             Ok(serialized_devices)
         }
-        Err(e) => Err(e.to_string()),
+        Err(e) => {
+            emit_if_session_expired(&app_handle, &e);
+            Err(e)
+        }
     }
 }
 
+#[tauri::command]
+pub async fn sync_devices(app_handle: tauri::AppHandle) -> Result<String, BambuApiError> {
+    println!("[commands::bambu::sync_devices] syncing devices with the cloud");
+
+    match crate::handlers::sync::sync_devices(&app_handle, BAMBU_CLIENT.borrow()).await {
+        Ok(changes) => {
+            serde_json::to_string(&changes).map_err(|e| BambuApiError::Other(e.to_string()))
+        }
+        Err(e) => {
+            emit_if_session_expired(&app_handle, &e);
+            Err(e)
+        }
+    }
+}
+
+// Run a device action, then bring the config up to date with what the cloud now reports
+async fn sync_after_device_action(
+    app_handle: &tauri::AppHandle,
+    result: Result<(), BambuApiError>,
+) -> Result<String, BambuApiError> {
+    let changes: Result<Vec<DeviceChange>, BambuApiError> = match result {
+        Ok(_) => crate::handlers::sync::sync_devices(app_handle, BAMBU_CLIENT.borrow()).await,
+        Err(e) => Err(e),
+    };
+
+    match changes {
+        Ok(changes) => {
+            serde_json::to_string(&changes).map_err(|e| BambuApiError::Other(e.to_string()))
+        }
+        Err(e) => {
+            emit_if_session_expired(app_handle, &e);
+            Err(e)
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn bind_device(
+    app_handle: tauri::AppHandle,
+    dev_id: String,
+    pin_code: String,
+    name: String,
+) -> Result<String, BambuApiError> {
+    println!(
+        "[commands::bambu::bind_device] binding device: {} as {}",
+        dev_id, name
+    );
+
+    let result = BAMBU_CLIENT.bind_device(&dev_id, &pin_code, &name).await;
+    sync_after_device_action(&app_handle, result).await
+}
+
+#[tauri::command]
+pub async fn rename_device(
+    app_handle: tauri::AppHandle,
+    dev_id: String,
+    name: String,
+) -> Result<String, BambuApiError> {
+    println!(
+        "[commands::bambu::rename_device] renaming device: {} to {}",
+        dev_id, name
+    );
+
+    let result = BAMBU_CLIENT.rename_device(&dev_id, &name).await;
+    sync_after_device_action(&app_handle, result).await
+}
+
+#[tauri::command]
+pub async fn unbind_device(
+    app_handle: tauri::AppHandle,
+    dev_id: String,
+) -> Result<String, BambuApiError> {
+    println!(
+        "[commands::bambu::unbind_device] unbinding device: {}",
+        dev_id
+    );
+
+    if let Err(e) = BAMBU_CLIENT.unbind_device(&dev_id).await {
+        emit_if_session_expired(&app_handle, &e);
+        return Err(e);
+    }
+
+    // The printer won't accept our credentials anymore, so stop watching it
+    {
+        let mut client = BAMBU_MQTT_CLIENT.lock().await;
+        if let Some(device) = client.get_watched_device(&dev_id) {
+            if let Err(e) = client.unwatch_device(device).await {
+                println!(
+                    "[commands::bambu::unbind_device] failed to unwatch device: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    let changes = forget_device(&app_handle, &dev_id)?;
+    serde_json::to_string(&changes).map_err(|e| BambuApiError::Other(e.to_string()))
+}
+
+#[tauri::command]
+pub async fn get_account_profile(app_handle: tauri::AppHandle) -> Result<String, BambuApiError> {
+    println!("[commands::bambu::get_account_profile] getting account profile");
+
+    let config_path = get_config_path()?;
+    let cached_profile = Config::load_or_create(&config_path)?.bambu_info.profile;
+
+    let profile = match BAMBU_CLIENT.get_account_profile().await {
+        Ok(profile) => {
+            // Only the profile, a refresh during the request saved a new session to the config
+            update_config(|config| {
+                config.bambu_info.profile = Some(profile.clone());
+                Ok(())
+            })?;
+            profile
+        }
+        // Offline, the cached profile is still right unless the user switched accounts
+        Err(BambuApiError::Network(e)) => match cached_profile {
+            Some(profile) => {
+                println!(
+                    "[commands::bambu::get_account_profile] cloud unreachable, using cached profile: {}",
+                    e
+                );
+                profile
+            }
+            None => return Err(BambuApiError::Network(e)),
+        },
+        Err(e) => {
+            emit_if_session_expired(&app_handle, &e);
+            return Err(e);
+        }
+    };
+
+    serde_json::to_string(&profile).map_err(|e| BambuApiError::Other(e.to_string()))
+}
+
+#[tauri::command]
+pub async fn fetch_task_history(
+    app_handle: tauri::AppHandle,
+    device_id: Option<String>,
+    offset: Option<u32>,
+    limit: Option<u32>,
+) -> Result<String, BambuApiError> {
+    println!(
+        "[commands::bambu::fetch_task_history] fetching task history for device: {:?}",
+        device_id
+    );
+
+    let offset = offset.unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_TASK_PAGE_SIZE);
+    let mut cache = TaskHistoryCache::load().unwrap_or_else(|e| {
+        println!(
+            "[commands::bambu::fetch_task_history] failed to load cache, starting fresh: {}",
+            e
+        );
+        TaskHistoryCache::default()
+    });
+
+    let (page, cached) = match BAMBU_CLIENT
+        .fetch_task_history(device_id.as_deref(), offset, limit)
+        .await
+    {
+        Ok(page) => {
+            cache.merge(&page.tasks);
+            if let Err(e) = cache.save() {
+                println!(
+                    "[commands::bambu::fetch_task_history] failed to save cache: {}",
+                    e
+                );
+            }
+
+            (page, false)
+        }
+        // Offline, serve what we have instead
+        Err(BambuApiError::Network(e)) => {
+            println!(
+                "[commands::bambu::fetch_task_history] cloud unreachable, using cache: {}",
+                e
+            );
+            (cache.page(device_id.as_deref(), offset, limit), true)
+        }
+        Err(e) => {
+            emit_if_session_expired(&app_handle, &e);
+            return Err(e);
+        }
+    };
+
+    let json = json!({
+        "total": page.total,
+        "tasks": page.tasks,
+        "cached": cached,
+        "cache_updated_at": cache.updated_at
+    });
+
+    serde_json::to_string(&json).map_err(|e| BambuApiError::Other(e.to_string()))
+}
+
 #[tauri::command]
 pub async fn discover_devices(devices: Vec<BambuDevice>) -> Result<String, String> {
     println!("[commands::bambu::discover_devices] discovering devices");
 
     let client = BAMBU_CLIENT.borrow();
-    let devices = client.get_device_ips(devices).await;
-    println!("[commands::bambu::discover_devices] devices: {:?}", devices);
+    let announcements = client.discover_announcements().await;
+    println!(
+        "[commands::bambu::discover_devices] announcements: {:?}",
+        announcements
+    );
 
-    match devices {
-        Ok(devices) => {
+    match announcements {
+        Ok(announcements) => {
+            // Announcements include printers that aren't on the account yet, e.g. to bind them
             let json = json!({
-                "devices": devices
+                "devices": match_announcements(devices, &announcements),
+                "announcements": announcements
             });
 
             let serialized_devices = serde_json::to_string(&json).map_err(|e| e.to_string())?;
@@ -91,14 +449,146 @@ pub async fn discover_devices(devices: Vec<BambuDevice>) -> Result<String, Strin
     }
 }
 
+// Pass firmware upgrade progress on to the frontend as upgrade-progress events
+async fn forward_upgrade_events(
+    app_handle: tauri::AppHandle,
+    mut events: tokio::sync::broadcast::Receiver<(BambuDevice, UpgradeState)>,
+) {
+    loop {
+        let (device, state) = match events.recv().await {
+            Ok(event) => event,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+
+        println!(
+            "[commands::bambu::task::upgrade_events] device: {} upgrade {} at {}%",
+            device.name, state.status, state.progress
+        );
+
+        let _ = app_handle.emit_all(
+            "upgrade-progress",
+            UpgradeProgressEvent {
+                dev_id: device.dev_id,
+                name: device.name,
+                state,
+            },
+        );
+    }
+}
+
+// Move the MQTT session of a watched printer over to its new address
+async fn reconnect_moved_devices(
+    app_handle: tauri::AppHandle,
+    mut ip_changes: tokio::sync::mpsc::UnboundedReceiver<DeviceIpChange>,
+) {
+    while let Some(change) = ip_changes.recv().await {
+        let _ = app_handle.emit_all("device-ip-changed", &change);
+
+        if BAMBU_MQTT_CLIENT
+            .lock()
+            .await
+            .get_watched_device(&change.device.dev_id)
+            .is_none()
+        {
+            continue;
+        }
+
+        println!(
+            "[commands::bambu::task::reconnect_moved_devices] reconnecting device: {} at {}",
+            change.device.name, change.new_ip
+        );
+
+        // Connecting can take a while with retries, the other devices need the client meanwhile
+        let mode = load_mqtt_mode();
+        let cloud = if mode == "lan" {
+            None
+        } else {
+            cloud_mqtt_credentials().await
+        };
+        let connection = BambuMQTTClient::connect(&change.device, &mode, cloud).await;
+
+        let mut client = BAMBU_MQTT_CLIENT.lock().await;
+
+        // Unwatched while we were connecting, the new connection is dropped
+        if client.get_watched_device(&change.device.dev_id).is_none() {
+            continue;
+        }
+
+        if let Err(e) = client.unwatch_device(change.device.clone()).await {
+            println!(
+                "[commands::bambu::task::reconnect_moved_devices] failed to disconnect device: {}: {}",
+                change.device.name, e
+            );
+        }
+
+        let result = match connection {
+            Ok(connection) => {
+                client
+                    .watch_connected(change.device.clone(), connection)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            println!(
+                "[commands::bambu::task::reconnect_moved_devices] failed to reconnect device: {}: {}",
+                change.device.name, e
+            );
+        }
+    }
+}
+
 #[tauri::command]
-pub async fn init_mqtt_worker() -> Result<String, String> {
+pub async fn get_discovered_devices() -> Result<String, String> {
+    let discovered = DISCOVERED_DEVICES.lock().await;
+    let announcements: Vec<_> = discovered.values().cloned().collect();
+    serde_json::to_string(&announcements).map_err(|e| e.to_string())
+}
+
+// Interfaces discovery can use, marked with the ones it currently does
+#[tauri::command]
+pub async fn list_network_interfaces() -> Result<String, String> {
+    let interfaces = load_discovery_settings()
+        .list_interfaces()
+        .map_err(|e| e.to_string())?;
+    serde_json::to_string(&interfaces).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn init_mqtt_worker(app_handle: tauri::AppHandle) -> Result<String, String> {
     println!("[commands::bambu::init_mqtt_worker] initializing mqtt worker");
 
     let result: Result<(), ()> = async {
         let mut client = BAMBU_MQTT_CLIENT.lock().await;
         client.initialize().await;
 
+        // Snapshots and timelapses follow job events from every watched device
+        let mut workers = EVENT_WORKERS.lock().await;
+        if workers.is_empty() {
+            workers.push(tokio::spawn(run_snapshot_worker(
+                client.subscribe_job_events(),
+                &CAMERA_MANAGER,
+            )));
+            workers.push(tokio::spawn(run_timelapse_worker(
+                client.subscribe_job_events(),
+                &CAMERA_MANAGER,
+            )));
+            workers.push(tokio::spawn(forward_upgrade_events(
+                app_handle.clone(),
+                client.subscribe_upgrade_events(),
+            )));
+
+            // Follow printers to their new address when DHCP moves them
+            let (ip_changes, ip_change_receiver) = tokio::sync::mpsc::unbounded_channel();
+            *DISCOVERY_SERVICE.lock().await = Some(spawn_discovery_service(ip_changes));
+            workers.push(tokio::spawn(reconnect_moved_devices(
+                app_handle,
+                ip_change_receiver,
+            )));
+        }
+
         Ok(())
     }
     .await;
@@ -109,6 +599,86 @@ pub async fn init_mqtt_worker() -> Result<String, String> {
     }
 }
 
+// The caller keeps the sender so a restarted service reports to the same receiver
+fn spawn_discovery_service(
+    ip_changes: UnboundedSender<DeviceIpChange>,
+) -> (tokio::task::JoinHandle<()>, UnboundedSender<DeviceIpChange>) {
+    let handle = tokio::spawn(run_discovery_service(
+        DISCOVERED_DEVICES.clone(),
+        ip_changes.clone(),
+    ));
+    (handle, ip_changes)
+}
+
+// The service reads its settings when it starts, so it's restarted when they're saved.
+// Nothing to do before init_mqtt_worker has started it
+pub(crate) async fn restart_discovery_service() {
+    let mut service = DISCOVERY_SERVICE.lock().await;
+    if let Some((handle, ip_changes)) = service.take() {
+        println!("[commands::bambu::restart_discovery_service] restarting discovery");
+        handle.abort();
+        *service = Some(spawn_discovery_service(ip_changes));
+    }
+}
+
+// An unknown mode in a hand edited config is treated as auto
+fn load_mqtt_mode() -> String {
+    get_config_path()
+        .and_then(|path| Config::load_or_create(&path))
+        .and_then(|config| {
+            config.cloud.validate_mqtt_mode()?;
+            Ok(config.cloud.mqtt_mode)
+        })
+        .unwrap_or_else(|_| "auto".to_string())
+}
+
+// Credentials for the cloud broker when signed in. Not being able to get them isn't fatal,
+// the device may still be reachable over LAN
+async fn cloud_mqtt_credentials() -> Option<BambuCloudMqttCredentials> {
+    let result: Result<BambuCloudMqttCredentials, BambuApiError> = async {
+        let config_path = get_config_path()?;
+        let cached_profile = Config::load_or_create(&config_path)?.bambu_info.profile;
+
+        let uid = match cached_profile {
+            Some(profile) => profile.uid,
+            None => {
+                let profile = BAMBU_CLIENT.get_account_profile().await?;
+
+                // Only the profile, a refresh during the request saved a new session to the config
+                update_config(|config| {
+                    config.bambu_info.profile = Some(profile.clone());
+                    Ok(())
+                })?;
+                profile.uid
+            }
+        };
+
+        BAMBU_CLIENT.ensure_valid_jwt().await?;
+        let token = BAMBU_CLIENT
+            .get_jwt()
+            .await
+            .ok_or_else(|| BambuApiError::Other("No jwt has been set".to_string()))?;
+
+        Ok(BambuCloudMqttCredentials {
+            broker_url: BAMBU_CLIENT.endpoints().mqtt_url,
+            uid,
+            token,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(credentials) => Some(credentials),
+        Err(e) => {
+            println!(
+                "[commands::bambu::cloud_mqtt_credentials] cloud MQTT is unavailable: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
 #[tauri::command]
 pub async fn watch_device(device: BambuDevice) -> Result<String, String> {
     println!(
@@ -116,9 +686,16 @@ pub async fn watch_device(device: BambuDevice) -> Result<String, String> {
         device.name
     );
 
+    let mode = load_mqtt_mode();
+    let cloud = if mode == "lan" {
+        None
+    } else {
+        cloud_mqtt_credentials().await
+    };
+
     let result: Result<(), std::io::Error> = async {
         let mut client = BAMBU_MQTT_CLIENT.lock().await;
-        client.watch_device(device).await
+        client.watch_device(device, &mode, cloud).await
     }
     .await;
 
@@ -163,6 +740,15 @@ pub async fn deinit_mqtt_worker() -> Result<String, String> {
     let result: Result<(), ()> = async {
         let mut client = BAMBU_MQTT_CLIENT.lock().await;
         client.deinitialize().await;
+
+        for handle in EVENT_WORKERS.lock().await.drain(..) {
+            handle.abort();
+        }
+
+        if let Some((handle, _)) = DISCOVERY_SERVICE.lock().await.take() {
+            handle.abort();
+        }
+
         Ok(())
     }
     .await;
@@ -172,3 +758,107 @@ pub async fn deinit_mqtt_worker() -> Result<String, String> {
         Err(_) => Err("Failed to deinitialize mqtt worker".to_string()),
     }
 }
+
+#[tauri::command]
+pub async fn suggest_ams_mapping(
+    dev_id: String,
+    filaments: Vec<ProjectFilament>,
+) -> Result<String, String> {
+    println!(
+        "[commands::bambu::suggest_ams_mapping] suggesting ams mapping for device: {} with {} filaments",
+        dev_id,
+        filaments.len()
+    );
+
+    let report = {
+        let client = BAMBU_MQTT_CLIENT.lock().await;
+        client.get_device_report(&dev_id).await
+    };
+
+    let report = match report {
+        Some(report) => report,
+        None => {
+            return Err(format!(
+            "No report has been received from device: {} yet. Please ensure it is being watched.",
+            dev_id
+        ))
+        }
+    };
+
+    let trays = AmsTray::from_report(&report);
+    let suggestion = suggest_mapping(&filaments, &trays);
+    println!(
+        "[commands::bambu::suggest_ams_mapping] suggestion: {:?}",
+        suggestion
+    );
+
+    serde_json::to_string(&suggestion).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_firmware_report(app_handle: tauri::AppHandle) -> Result<String, BambuApiError> {
+    println!("[commands::bambu::get_firmware_report] building firmware report");
+
+    let config = Config::load_or_create(&get_config_path()?)?;
+    let devices: Vec<BambuDevice> = config
+        .bambu_devices
+        .into_iter()
+        .filter(|d| !d.removed)
+        .collect();
+
+    // Ask every watched printer first, they answer while we talk to the cloud
+    let mut modules: HashMap<String, Result<Vec<ModuleVersion>, String>> = HashMap::new();
+    {
+        let client = BAMBU_MQTT_CLIENT.lock().await;
+        for device in devices.iter() {
+            if let Err(e) = client.request_versions(&device.dev_id).await {
+                modules.insert(device.dev_id.clone(), Err(e.to_string()));
+            }
+        }
+    }
+
+    let latest = futures::future::join_all(
+        devices
+            .iter()
+            .map(|d| BAMBU_CLIENT.get_latest_firmware(&d.dev_id)),
+    )
+    .await;
+
+    let deadline = tokio::time::Instant::now() + VERSION_REQUEST_TIMEOUT;
+    while modules.len() < devices.len() && tokio::time::Instant::now() < deadline {
+        {
+            let client = BAMBU_MQTT_CLIENT.lock().await;
+            for device in devices.iter() {
+                if modules.contains_key(&device.dev_id) {
+                    continue;
+                }
+
+                let report = client.get_device_report(&device.dev_id).await;
+                if let Some(versions) = report.as_ref().and_then(parse_module_versions) {
+                    modules.insert(device.dev_id.clone(), Ok(versions));
+                }
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    let mut report = vec![];
+    for (device, latest) in devices.iter().zip(latest) {
+        let device_modules = modules.remove(&device.dev_id).unwrap_or_else(|| {
+            Err("The printer did not answer the version request in time".to_string())
+        });
+
+        let latest = match latest {
+            Ok(latest) => Ok(latest),
+            Err(e) => {
+                emit_if_session_expired(&app_handle, &e);
+                Err(e.to_string())
+            }
+        };
+
+        report.push(build_report_entry(device, device_modules, latest));
+    }
+
+    serde_json::to_string(&report).map_err(|e| BambuApiError::Other(e.to_string()))
+}
@@ -0,0 +1,234 @@
+use crate::handlers::bambu::BambuDevice;
+use crate::handlers::camera::CameraManager;
+use crate::handlers::config::{get_config_dir, get_config_path, Config};
+use crate::handlers::mjpeg::MjpegServer;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::Manager;
+use tokio::sync::Mutex;
+
+// Used when the frontend doesn't ask for a specific frame rate
+const DEFAULT_MAX_FPS: u32 = 10;
+
+lazy_static! {
+    pub(crate) static ref CAMERA_MANAGER: CameraManager = CameraManager::new();
+    static ref CAMERA_FORWARDERS: Mutex<HashMap<String, tokio::task::JoinHandle<()>>> =
+        Mutex::new(HashMap::new());
+    static ref MJPEG_SERVER: Mutex<Option<MjpegServer>> = Mutex::new(None);
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+struct CameraFrameEvent {
+    dev_id: String,
+    sequence: u64,
+    captured_at: i64,
+    size: usize,
+}
+
+fn load_config() -> Result<Config, String> {
+    let config_path = get_config_path().map_err(|e| e.to_string())?;
+    Config::load_or_create(&config_path).map_err(|e| e.to_string())
+}
+
+pub(crate) fn find_device(dev_id: &str) -> Result<BambuDevice, String> {
+    load_config()?
+        .find_device(dev_id)
+        .cloned()
+        .ok_or_else(|| format!("No device with id: {} was found in the config", dev_id))
+}
+
+#[tauri::command]
+pub async fn start_camera(
+    app_handle: tauri::AppHandle,
+    dev_id: String,
+    max_fps: Option<u32>,
+) -> Result<String, String> {
+    println!(
+        "[commands::camera::start_camera] starting camera for device: {} (max fps: {:?})",
+        dev_id, max_fps
+    );
+
+    let device = find_device(&dev_id)?;
+    let mut forwarders = CAMERA_FORWARDERS.lock().await;
+
+    // Already forwarding this camera, just restart with the new frame rate
+    if let Some(handle) = forwarders.remove(&dev_id) {
+        handle.abort();
+        CAMERA_MANAGER.release(&dev_id);
+    }
+
+    let mut receiver = CAMERA_MANAGER
+        .subscribe(&device)
+        .map_err(|e| e.to_string())?;
+    let frame_interval = Duration::from_secs(1) / max_fps.unwrap_or(DEFAULT_MAX_FPS).max(1);
+
+    // The frames themselves are served over the camera:// protocol, events only say a new one is ready
+    let handle = tokio::spawn(async move {
+        while receiver.changed().await.is_ok() {
+            let frame = receiver.borrow_and_update().clone();
+
+            if let Some(frame) = frame {
+                let event = CameraFrameEvent {
+                    dev_id: frame.dev_id.clone(),
+                    sequence: frame.sequence,
+                    captured_at: frame.captured_at,
+                    size: frame.jpeg.len(),
+                };
+
+                let _ = app_handle.emit_all("camera-frame", event);
+            }
+
+            tokio::time::sleep(frame_interval).await;
+        }
+    });
+
+    forwarders.insert(dev_id, handle);
+    Ok("".to_string())
+}
+
+#[tauri::command]
+pub async fn stop_camera(dev_id: String) -> Result<String, String> {
+    println!(
+        "[commands::camera::stop_camera] stopping camera for device: {}",
+        dev_id
+    );
+
+    let mut forwarders = CAMERA_FORWARDERS.lock().await;
+    if let Some(handle) = forwarders.remove(&dev_id) {
+        handle.abort();
+        CAMERA_MANAGER.release(&dev_id);
+    }
+
+    Ok("".to_string())
+}
+
+#[tauri::command]
+pub async fn capture_snapshot(dev_id: String) -> Result<String, String> {
+    println!(
+        "[commands::camera::capture_snapshot] capturing snapshot for device: {}",
+        dev_id
+    );
+
+    let device = find_device(&dev_id)?;
+    let jpeg = CAMERA_MANAGER.snapshot(&device).await.map_err(|e| {
+        println!(
+            "[commands::camera::capture_snapshot] error capturing snapshot: {:?}",
+            e
+        );
+        e.to_string()
+    })?;
+
+    let mut path = get_config_dir().map_err(|e| e.to_string())?;
+    path.push("snapshots");
+    path.push(&dev_id);
+    tokio::fs::create_dir_all(&path)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    path.push(format!(
+        "{}.jpg",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
+    ));
+    tokio::fs::write(&path, jpeg)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    println!(
+        "[commands::camera::capture_snapshot] saved snapshot to: {}",
+        path.display()
+    );
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[tauri::command]
+pub async fn start_mjpeg_server() -> Result<String, String> {
+    println!("[commands::camera::start_mjpeg_server] starting mjpeg server");
+
+    let config = load_config()?;
+    let mut server = MJPEG_SERVER.lock().await;
+
+    // Restart so changes to the bind address or port are picked up
+    if let Some(server) = server.take() {
+        server.stop();
+    }
+
+    let started = MjpegServer::start(&config.mjpeg_server, &CAMERA_MANAGER)
+        .await
+        .map_err(|e| {
+            println!(
+                "[commands::camera::start_mjpeg_server] error starting mjpeg server: {:?}",
+                e
+            );
+            e.to_string()
+        })?;
+
+    let address = format!("http://{}", started.address());
+    *server = Some(started);
+
+    Ok(address)
+}
+
+#[tauri::command]
+pub async fn stop_mjpeg_server() -> Result<String, String> {
+    println!("[commands::camera::stop_mjpeg_server] stopping mjpeg server");
+
+    if let Some(server) = MJPEG_SERVER.lock().await.take() {
+        server.stop();
+    }
+
+    Ok("".to_string())
+}
+
+#[tauri::command]
+pub async fn get_mjpeg_server_address() -> Result<Option<String>, String> {
+    let server = MJPEG_SERVER.lock().await;
+    Ok(server
+        .as_ref()
+        .map(|server| format!("http://{}", server.address())))
+}
+
+// Called on startup, the server only runs when enabled in the config
+pub async fn start_mjpeg_server_if_enabled() {
+    let enabled = load_config()
+        .map(|config| config.mjpeg_server.enabled)
+        .unwrap_or(false);
+
+    if enabled {
+        if let Err(e) = start_mjpeg_server().await {
+            println!(
+                "[commands::camera::start_mjpeg_server_if_enabled] failed to start mjpeg server: {}",
+                e
+            );
+        }
+    }
+}
+
+// Serves the latest frame of a camera at camera://localhost/<dev_id> (https://camera.localhost/<dev_id> on Windows)
+pub fn handle_camera_protocol(
+    _app_handle: &tauri::AppHandle,
+    request: &tauri::http::Request,
+) -> Result<tauri::http::Response, Box<dyn std::error::Error>> {
+    let uri = request.uri();
+    let dev_id = uri
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or("");
+
+    match CAMERA_MANAGER.latest_frame(dev_id) {
+        Some(frame) => tauri::http::ResponseBuilder::new()
+            .status(200)
+            .mimetype("image/jpeg")
+            .header("Cache-Control", "no-store")
+            .body(frame.jpeg.clone()),
+        None => tauri::http::ResponseBuilder::new()
+            .status(404)
+            .mimetype("text/plain")
+            .body(format!("No camera frame available for device: {}", dev_id).into_bytes()),
+    }
+}
@@ -1,4 +1,5 @@
-use crate::handlers::config::{get_config_path, Config};
+use crate::commands::bambu::{restart_discovery_service, BAMBU_CLIENT};
+use crate::handlers::config::{get_config_path, update_config, Config};
 
 #[tauri::command]
 pub fn init_config() -> Result<(), String> {
@@ -30,9 +31,41 @@ pub fn get_config() -> Result<Config, String> {
 }
 
 #[tauri::command]
-pub fn save_config(config: Config) -> Result<(), String> {
-    let config_path = get_config_path().map_err(|e| e.to_string())?;
-    config.save(&config_path).map_err(|e| e.to_string())?;
+pub fn save_config(mut config: Config) -> Result<(), String> {
+    // Reject bad endpoints before saving them, otherwise the client would quietly fall back to global
+    let endpoints = config.cloud.endpoints().map_err(|e| e.to_string())?;
+    config
+        .cloud
+        .validate_mqtt_mode()
+        .map_err(|e| e.to_string())?;
+    config.network.validate().map_err(|e| e.to_string())?;
+
+    let request_policy = config.cloud.request_policy.clone();
+    let network = config.network.clone();
+
+    let discovery_changed = update_config(|current| {
+        let discovery_changed = current.discovery != config.discovery;
+
+        // Settings pages send back the session they loaded, keep one refreshed since then
+        let session_is_stale = config.bambu_info.jwt_last_refresh != 0
+            && config.bambu_info.jwt_last_refresh < current.bambu_info.jwt_last_refresh;
+        if session_is_stale {
+            std::mem::swap(&mut config.bambu_info, &mut current.bambu_info);
+        }
+
+        *current = config;
+        Ok(discovery_changed)
+    })
+    .map_err(|e| e.to_string())?;
+
+    if discovery_changed {
+        tauri::async_runtime::spawn(restart_discovery_service());
+    }
+
+    BAMBU_CLIENT.set_endpoints(endpoints);
+    BAMBU_CLIENT
+        .set_http_settings(request_policy, &network)
+        .map_err(|e| e.to_string())?;
 
     // Return the saved config
     Ok(())
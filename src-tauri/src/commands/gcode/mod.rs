@@ -0,0 +1,78 @@
+use crate::handlers::bambu::BambuDevice;
+use crate::handlers::ftps::BambuFtpsClient;
+use crate::handlers::gcode::{analyze, load_gcode, load_local_gcode, GcodeAnalysis};
+use std::path::PathBuf;
+
+// Parsing large plates is CPU heavy, so keep it off the async workers
+async fn analyze_blocking<F>(load: F, include_svg: bool) -> Result<GcodeAnalysis, String>
+where
+    F: FnOnce() -> Result<String, std::io::Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let gcode = load().map_err(|e| e.to_string())?;
+        Ok(analyze(&gcode, include_svg))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn analyze_gcode(
+    path: String,
+    plate: Option<u32>,
+    include_svg: bool,
+) -> Result<String, String> {
+    println!(
+        "[commands::gcode::analyze_gcode] analyzing gcode: {} (plate: {:?})",
+        path, plate
+    );
+
+    let local_path = PathBuf::from(path);
+    let analysis =
+        analyze_blocking(move || load_local_gcode(&local_path, plate), include_svg).await?;
+
+    println!(
+        "[commands::gcode::analyze_gcode] analyzed {} layers",
+        analysis.layer_count
+    );
+
+    serde_json::to_string(&analysis).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn analyze_printer_gcode(
+    device: BambuDevice,
+    path: String,
+    plate: Option<u32>,
+    include_svg: bool,
+) -> Result<String, String> {
+    println!(
+        "[commands::gcode::analyze_printer_gcode] analyzing gcode: {} (plate: {:?}) on device: {}",
+        path, plate, device.name
+    );
+
+    let data: Result<Vec<u8>, std::io::Error> = async {
+        let mut client = BambuFtpsClient::connect(&device).await?;
+        let data = client.retrieve(&path).await;
+        client.quit().await;
+        data
+    }
+    .await;
+
+    let data = data.map_err(|e| {
+        println!(
+            "[commands::gcode::analyze_printer_gcode] error downloading {}: {:?}",
+            path, e
+        );
+        e.to_string()
+    })?;
+
+    let analysis = analyze_blocking(move || load_gcode(data, &path, plate), include_svg).await?;
+
+    println!(
+        "[commands::gcode::analyze_printer_gcode] analyzed {} layers",
+        analysis.layer_count
+    );
+
+    serde_json::to_string(&analysis).map_err(|e| e.to_string())
+}
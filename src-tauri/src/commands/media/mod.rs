@@ -0,0 +1,70 @@
+use crate::handlers::bambu::BambuDevice;
+use crate::handlers::config::{get_config_path, get_media_download_dir, Config};
+use crate::handlers::media::{list_media, MediaDownloadManager, MediaFile};
+use lazy_static::lazy_static;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+lazy_static! {
+    static ref MEDIA_DOWNLOAD_MANAGER: Mutex<MediaDownloadManager> =
+        Mutex::new(MediaDownloadManager::new());
+}
+
+#[tauri::command]
+pub async fn list_printer_media(device: BambuDevice) -> Result<String, String> {
+    println!(
+        "[commands::media::list_printer_media] listing media on device: {}",
+        device.name
+    );
+
+    let files = list_media(&device).await.map_err(|e| {
+        println!(
+            "[commands::media::list_printer_media] error listing media: {:?}",
+            e
+        );
+        e.to_string()
+    })?;
+
+    let json = json!({
+        "files": files
+    });
+
+    serde_json::to_string(&json).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn download_printer_media(
+    app_handle: tauri::AppHandle,
+    device: BambuDevice,
+    files: Vec<MediaFile>,
+    delete_after: bool,
+) -> Result<u32, String> {
+    println!(
+        "[commands::media::download_printer_media] downloading {} files from device: {} (delete after: {})",
+        files.len(),
+        device.name,
+        delete_after
+    );
+
+    let config_path = get_config_path().map_err(|e| e.to_string())?;
+    let config = Config::load_or_create(&config_path).map_err(|e| e.to_string())?;
+    let destination = get_media_download_dir(&config).map_err(|e| e.to_string())?;
+
+    let mut manager = MEDIA_DOWNLOAD_MANAGER.lock().await;
+    let download_id = manager
+        .start(app_handle, device, files, destination, delete_after)
+        .await;
+
+    Ok(download_id)
+}
+
+#[tauri::command]
+pub async fn cancel_media_download(download_id: u32) -> Result<bool, String> {
+    println!(
+        "[commands::media::cancel_media_download] cancelling download: {}",
+        download_id
+    );
+
+    let mut manager = MEDIA_DOWNLOAD_MANAGER.lock().await;
+    Ok(manager.cancel(download_id).await)
+}
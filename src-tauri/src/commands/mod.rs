@@ -1,3 +1,6 @@
 pub mod bambu;
+pub mod camera;
 pub mod config;
+pub mod gcode;
+pub mod media;
 pub mod util;
@@ -1,2 +1,10 @@
 pub static BAMBU_API_URL: &str = "https://api.bambulab.com";
 pub static BAMBU_LOGIN_URL: &str = "https://bambulab.com/api/sign-in/form";
+pub static BAMBU_TFA_URL: &str = "https://bambulab.com/api/sign-in/tfa";
+pub static BAMBU_MQTT_URL: &str = "mqtts://us.mqtt.bambulab.com:8883";
+
+// Accounts registered in mainland China live on a separate cloud
+pub static BAMBU_CN_API_URL: &str = "https://api.bambulab.cn";
+pub static BAMBU_CN_LOGIN_URL: &str = "https://bambulab.cn/api/sign-in/form";
+pub static BAMBU_CN_TFA_URL: &str = "https://bambulab.cn/api/sign-in/tfa";
+pub static BAMBU_CN_MQTT_URL: &str = "mqtts://cn.mqtt.bambulab.com:8883";
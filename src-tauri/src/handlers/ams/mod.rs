@@ -0,0 +1,462 @@
+use serde_json::Value;
+
+// The tray id the printer uses for the external spool holder
+pub const EXTERNAL_TRAY_ID: i32 = 254;
+
+// Penalties added on top of the colour distance when scoring a tray
+const MATERIAL_FAMILY_PENALTY: f64 = 25.0;
+const LOW_REMAINING_PENALTY: f64 = 15.0;
+const EMPTY_TRAY_PENALTY: f64 = 50.0;
+const LOW_REMAINING_THRESHOLD: i32 = 10;
+
+// Colour distances (CIE76 delta E) above this are reported as a poor match
+const POOR_COLOR_MATCH_DISTANCE: f64 = 30.0;
+
+// A filament slot as defined by the project (3MF slice_info.config)
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ProjectFilament {
+    pub id: u32, // 1-based, as in the 3MF
+    pub filament_type: String,
+    pub color: String,
+    pub used_g: Option<f64>,
+}
+
+// A tray as reported by the printer in print.ams / print.vt_tray
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct AmsTray {
+    pub ams_id: i32,
+    pub tray_id: i32,
+    pub tray_type: String,
+    pub tray_color: String,
+    pub remain: i32, // -1 when the printer doesn't know
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct AmsSlotAssignment {
+    pub filament_id: u32,
+    pub filament_type: String,
+    pub color: String,
+    pub satisfied: bool,
+    pub tray: Option<i32>,
+    pub tray_type: Option<String>,
+    pub tray_color: Option<String>,
+    pub tray_remain: Option<i32>,
+    pub color_distance: Option<f64>,
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct AmsMappingSuggestion {
+    pub ams_mapping: Vec<i32>,
+    pub slots: Vec<AmsSlotAssignment>,
+}
+
+impl AmsTray {
+    // The value the printer expects in ams_mapping for this tray
+    pub fn mapping_id(&self) -> i32 {
+        if self.ams_id == EXTERNAL_TRAY_ID {
+            EXTERNAL_TRAY_ID
+        } else {
+            self.ams_id * 4 + self.tray_id
+        }
+    }
+
+    // Whether the tray holds anything that can be printed with
+    fn is_loaded(&self) -> bool {
+        !self.tray_type.trim().is_empty()
+    }
+
+    fn describe(&self) -> String {
+        if self.ams_id == EXTERNAL_TRAY_ID {
+            "external spool".to_string()
+        } else {
+            format!("AMS {} tray {}", self.ams_id + 1, self.tray_id + 1)
+        }
+    }
+
+    // Collect all loaded trays from a (merged) device report
+    pub fn from_report(report: &Value) -> Vec<AmsTray> {
+        let mut trays = vec![];
+        let print = &report["print"];
+
+        if let Some(units) = print["ams"]["ams"].as_array() {
+            for unit in units {
+                let ams_id = match parse_id(&unit["id"]) {
+                    Some(id) => id,
+                    None => continue,
+                };
+
+                for tray in unit["tray"].as_array().into_iter().flatten() {
+                    if let Some(tray) = Self::from_tray_value(ams_id, tray) {
+                        trays.push(tray);
+                    }
+                }
+            }
+        }
+
+        if print["vt_tray"].is_object() {
+            if let Some(tray) = Self::from_tray_value(EXTERNAL_TRAY_ID, &print["vt_tray"]) {
+                trays.push(tray);
+            }
+        }
+
+        trays
+    }
+
+    fn from_tray_value(ams_id: i32, tray: &Value) -> Option<AmsTray> {
+        // Empty trays are reported with only their id
+        let tray_type = tray["tray_type"].as_str().unwrap_or("").trim();
+        if tray_type.is_empty() {
+            return None;
+        }
+
+        let tray_id = if ams_id == EXTERNAL_TRAY_ID {
+            0
+        } else {
+            parse_id(&tray["id"])?
+        };
+
+        Some(AmsTray {
+            ams_id,
+            tray_id,
+            tray_type: tray_type.to_string(),
+            tray_color: tray["tray_color"].as_str().unwrap_or("").to_string(),
+            remain: tray["remain"].as_i64().map(|r| r as i32).unwrap_or(-1),
+        })
+    }
+}
+
+// Ids are sent as strings by most firmwares, but accept numbers too
+fn parse_id(value: &Value) -> Option<i32> {
+    match value {
+        Value::String(s) => s.parse::<i32>().ok(),
+        Value::Number(n) => n.as_i64().map(|n| n as i32),
+        _ => None,
+    }
+}
+
+fn normalize_material(material: &str) -> String {
+    material.trim().to_uppercase()
+}
+
+// The base material, e.g. "PLA" for "PLA-CF" or "PLA Matte"
+fn material_family(material: &str) -> String {
+    normalize_material(material)
+        .split(['-', ' '])
+        .next()
+        .unwrap_or("")
+        .to_string()
+}
+
+// Parse "#RRGGBB", "RRGGBB" or "RRGGBBAA" into an RGB triple
+fn parse_color(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.trim().trim_start_matches('#');
+    if hex.len() != 6 && hex.len() != 8 {
+        return None;
+    }
+
+    let r = u8::from_str_radix(hex.get(0..2)?, 16).ok()?;
+    let g = u8::from_str_radix(hex.get(2..4)?, 16).ok()?;
+    let b = u8::from_str_radix(hex.get(4..6)?, 16).ok()?;
+    Some((r, g, b))
+}
+
+// Convert sRGB to CIE L*a*b* (D65)
+fn rgb_to_lab((r, g, b): (u8, u8, u8)) -> (f64, f64, f64) {
+    fn linearize(c: u8) -> f64 {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    fn f(t: f64) -> f64 {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    }
+
+    let (r, g, b) = (linearize(r), linearize(g), linearize(b));
+    let x = (r * 0.4124 + g * 0.3576 + b * 0.1805) / 0.95047;
+    let y = r * 0.2126 + g * 0.7152 + b * 0.0722;
+    let z = (r * 0.0193 + g * 0.1192 + b * 0.9505) / 1.08883;
+
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+// CIE76 delta E between two colours, None if either can't be parsed
+fn color_distance(a: &str, b: &str) -> Option<f64> {
+    let (l1, a1, b1) = rgb_to_lab(parse_color(a)?);
+    let (l2, a2, b2) = rgb_to_lab(parse_color(b)?);
+    Some(((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt())
+}
+
+struct Candidate {
+    filament_index: usize,
+    tray_index: usize,
+    score: f64,
+    color_distance: Option<f64>,
+}
+
+// Score a filament/tray pair, lower is better. None if the materials are incompatible.
+fn score(filament: &ProjectFilament, tray: &AmsTray) -> Option<(f64, Option<f64>)> {
+    let mut score = 0.0;
+
+    if normalize_material(&filament.filament_type) != normalize_material(&tray.tray_type) {
+        if material_family(&filament.filament_type) != material_family(&tray.tray_type) {
+            return None;
+        }
+
+        score += MATERIAL_FAMILY_PENALTY;
+    }
+
+    if tray.remain == 0 {
+        score += EMPTY_TRAY_PENALTY;
+    } else if tray.remain > 0 && tray.remain < LOW_REMAINING_THRESHOLD {
+        score += LOW_REMAINING_PENALTY;
+    }
+
+    // Unparseable colours are treated as a poor match rather than rejected
+    let distance = color_distance(&filament.color, &tray.tray_color);
+    score += distance.unwrap_or(POOR_COLOR_MATCH_DISTANCE);
+
+    Some((score, distance))
+}
+
+// Propose an ams_mapping for the project's filaments using the printer's trays.
+// Each filament prefers its own tray; trays are only shared when nothing else fits.
+pub fn suggest_mapping(filaments: &[ProjectFilament], trays: &[AmsTray]) -> AmsMappingSuggestion {
+    // Empty trays have no material to match against
+    let trays: Vec<&AmsTray> = trays.iter().filter(|t| t.is_loaded()).collect();
+    let mut candidates: Vec<Candidate> = vec![];
+
+    for (filament_index, filament) in filaments.iter().enumerate() {
+        for (tray_index, tray) in trays.iter().enumerate() {
+            if let Some((score, color_distance)) = score(filament, tray) {
+                candidates.push(Candidate {
+                    filament_index,
+                    tray_index,
+                    score,
+                    color_distance,
+                });
+            }
+        }
+    }
+
+    candidates.sort_by(|a, b| {
+        a.score
+            .partial_cmp(&b.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    // First pass: best pairs first, one filament per tray
+    let mut assigned: Vec<Option<&Candidate>> = vec![None; filaments.len()];
+    let mut used_trays = vec![false; trays.len()];
+
+    for candidate in candidates.iter() {
+        if assigned[candidate.filament_index].is_none() && !used_trays[candidate.tray_index] {
+            assigned[candidate.filament_index] = Some(candidate);
+            used_trays[candidate.tray_index] = true;
+        }
+    }
+
+    // Second pass: let the leftovers share their best compatible tray
+    let mut shared = vec![false; filaments.len()];
+    for candidate in candidates.iter() {
+        if assigned[candidate.filament_index].is_none() {
+            assigned[candidate.filament_index] = Some(candidate);
+            shared[candidate.filament_index] = true;
+        }
+    }
+
+    let mapping_len = filaments.iter().map(|f| f.id as usize).max().unwrap_or(0);
+    let mut ams_mapping = vec![-1; mapping_len];
+    let mut slots = vec![];
+
+    for (index, filament) in filaments.iter().enumerate() {
+        let mut slot = AmsSlotAssignment {
+            filament_id: filament.id,
+            filament_type: filament.filament_type.clone(),
+            color: filament.color.clone(),
+            satisfied: false,
+            tray: None,
+            tray_type: None,
+            tray_color: None,
+            tray_remain: None,
+            color_distance: None,
+            notes: vec![],
+        };
+
+        match assigned[index] {
+            Some(candidate) => {
+                let tray = trays[candidate.tray_index];
+
+                slot.satisfied = true;
+                slot.tray = Some(tray.mapping_id());
+                slot.tray_type = Some(tray.tray_type.clone());
+                slot.tray_color = Some(tray.tray_color.clone());
+                slot.tray_remain = Some(tray.remain);
+                slot.color_distance = candidate.color_distance;
+
+                if normalize_material(&filament.filament_type)
+                    != normalize_material(&tray.tray_type)
+                {
+                    slot.notes.push(format!(
+                        "No {} loaded, using {} from the same material family in {}",
+                        filament.filament_type,
+                        tray.tray_type,
+                        tray.describe()
+                    ));
+                }
+
+                match candidate.color_distance {
+                    Some(distance) if distance > POOR_COLOR_MATCH_DISTANCE => {
+                        slot.notes.push(format!(
+                            "Closest colour is {} in {} (delta E {:.1})",
+                            tray.tray_color,
+                            tray.describe(),
+                            distance
+                        ));
+                    }
+                    None => slot.notes.push(format!(
+                        "Could not compare colours {} and {}",
+                        filament.color, tray.tray_color
+                    )),
+                    _ => {}
+                }
+
+                if tray.remain == 0 {
+                    slot.notes
+                        .push(format!("{} reports no filament remaining", tray.describe()));
+                } else if tray.remain > 0 && tray.remain < LOW_REMAINING_THRESHOLD {
+                    slot.notes.push(format!(
+                        "{} only has {}% remaining",
+                        tray.describe(),
+                        tray.remain
+                    ));
+                }
+
+                if shared[index] {
+                    slot.notes.push(format!(
+                        "Shares {} with another filament, not enough matching trays loaded",
+                        tray.describe()
+                    ));
+                }
+
+                if filament.id > 0 {
+                    ams_mapping[filament.id as usize - 1] = tray.mapping_id();
+                }
+            }
+            None => {
+                if trays.is_empty() {
+                    slot.notes
+                        .push("The printer did not report any loaded trays".to_string());
+                } else {
+                    slot.notes.push(format!(
+                        "No tray with {} (or a compatible material) is loaded",
+                        filament.filament_type
+                    ));
+                }
+            }
+        }
+
+        slots.push(slot);
+    }
+
+    AmsMappingSuggestion { ams_mapping, slots }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filament(id: u32, filament_type: &str, color: &str) -> ProjectFilament {
+        ProjectFilament {
+            id,
+            filament_type: filament_type.to_string(),
+            color: color.to_string(),
+            used_g: None,
+        }
+    }
+
+    fn tray(ams_id: i32, tray_id: i32, tray_type: &str, color: &str, remain: i32) -> AmsTray {
+        AmsTray {
+            ams_id,
+            tray_id,
+            tray_type: tray_type.to_string(),
+            tray_color: color.to_string(),
+            remain,
+        }
+    }
+
+    #[test]
+    fn reads_trays_and_external_spool_from_report() {
+        let report = json!({
+            "print": {
+                "ams": { "ams": [{ "id": "1", "tray": [
+                    { "id": "0", "tray_type": "PLA", "tray_color": "FF0000FF", "remain": 80 },
+                    { "id": "1" }
+                ]}]},
+                "vt_tray": { "id": "254", "tray_type": "PETG", "tray_color": "000000FF" }
+            }
+        });
+
+        let trays = AmsTray::from_report(&report);
+        assert_eq!(trays.len(), 2);
+        assert_eq!(trays[0].mapping_id(), 4);
+        assert_eq!(trays[0].remain, 80);
+        assert_eq!(trays[1].mapping_id(), EXTERNAL_TRAY_ID);
+        assert_eq!(trays[1].remain, -1);
+    }
+
+    #[test]
+    fn prefers_exact_material_and_closest_colour() {
+        let filaments = vec![filament(1, "PLA", "#FF0000")];
+        let trays = vec![
+            tray(0, 0, "PLA", "0000FFFF", 100),
+            tray(0, 1, "PLA", "F00000FF", 100),
+            tray(0, 2, "PLA-CF", "FF0000FF", 100),
+        ];
+
+        let suggestion = suggest_mapping(&filaments, &trays);
+        assert_eq!(suggestion.ams_mapping, vec![1]);
+        assert!(suggestion.slots[0].satisfied);
+    }
+
+    #[test]
+    fn rejects_other_materials_and_skips_empty_trays() {
+        let filaments = vec![filament(2, "PETG", "#FFFFFF")];
+        let trays = vec![
+            tray(0, 0, "PLA", "FFFFFFFF", 100),
+            tray(0, 1, " ", "FFFFFFFF", 100),
+        ];
+
+        let suggestion = suggest_mapping(&filaments, &trays);
+        assert_eq!(suggestion.ams_mapping, vec![-1, -1]);
+        assert!(!suggestion.slots[0].satisfied);
+        assert_eq!(suggestion.slots[0].tray, None);
+    }
+
+    #[test]
+    fn shares_a_tray_only_when_nothing_else_fits() {
+        let filaments = vec![filament(1, "PLA", "#000000"), filament(2, "PLA", "#111111")];
+        let trays = vec![tray(0, 3, "PLA", "000000FF", 5)];
+
+        let suggestion = suggest_mapping(&filaments, &trays);
+        assert_eq!(suggestion.ams_mapping, vec![3, 3]);
+        assert!(suggestion.slots[1]
+            .notes
+            .iter()
+            .any(|n| n.starts_with("Shares")));
+        assert!(suggestion.slots[0]
+            .notes
+            .iter()
+            .any(|n| n.contains("5% remaining")));
+    }
+}
@@ -1,21 +1,245 @@
 // Imports
-use super::ssdp::SsdpMessage;
+use super::config::{get_config_path, update_config, Config};
+use super::discovery::load_discovery_settings;
+use super::firmware::{blocked_during_upgrade, UpgradeState};
+use super::jobs::{save_records, JobEvent, JobTracker};
+use super::network::{load_network_settings, NetworkSettings};
+use super::ssdp::{BambuAnnouncement, SsdpMessage, SsdpSearcher, BAMBU_SEARCH_TARGET};
 use crate::constants;
 use crate::handlers::ssdp::SsdpListener;
 use futures::{StreamExt, TryFutureExt};
-use serde_json::{json, Number};
+use serde_json::{json, Number, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, watch, Mutex};
+
+// Refresh the jwt once it is this close to expiring
+const JWT_REFRESH_MARGIN_SECS: i64 = 24 * 60 * 60;
+
+// How long auto mode waits on the LAN before falling back to the cloud broker
+const LAN_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub struct BambuClient {
-    client: reqwest::Client,
+    client: RwLock<reqwest::Client>,
+    request_policy: RwLock<BambuRequestPolicy>,
+    cancellable: std::sync::Mutex<HashMap<String, watch::Sender<bool>>>, // Frontend calls by request id
     jwt: Mutex<Option<String>>,
+    refreshing: Mutex<()>, // Held while refreshing, so a token pair is only ever redeemed once
+    jwt_expires_at: Mutex<Option<i64>>,
+    refresh_token: Mutex<Option<String>>,
+    pending_login: Mutex<Option<PendingLogin>>,
+    endpoints: RwLock<BambuEndpoints>,
+}
+
+// Which Bambu cloud to talk to. Custom points every request at the given URLs, e.g. a local mock server
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct BambuCloudConfig {
+    pub region: String, // global, china or custom
+    #[serde(default)]
+    pub api_url: String,
+    #[serde(default)]
+    pub login_url: String,
+    #[serde(default)]
+    pub tfa_url: String,
+    #[serde(default)]
+    pub mqtt_url: String, // Overrides the region's broker in any region, e.g. mqtt://localhost:1883
+    #[serde(default = "default_mqtt_mode")]
+    pub mqtt_mode: String, // auto (LAN, then cloud), lan or cloud
+    #[serde(default)]
+    pub request_policy: BambuRequestPolicy,
+}
+
+// Timeouts and retries applied to every cloud request
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct BambuRequestPolicy {
+    pub connect_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub max_retry_after_secs: u64, // Longer Retry-After waits are returned as rate limited instead
+}
+
+impl Default for BambuRequestPolicy {
+    fn default() -> Self {
+        BambuRequestPolicy {
+            connect_timeout_secs: 10,
+            request_timeout_secs: 30,
+            max_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            max_retry_after_secs: 60,
+        }
+    }
+}
+
+impl BambuRequestPolicy {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(backoff)
+    }
+}
+
+fn default_mqtt_mode() -> String {
+    "auto".to_string()
+}
+
+impl Default for BambuCloudConfig {
+    fn default() -> Self {
+        BambuCloudConfig {
+            region: "global".to_string(),
+            api_url: String::new(),
+            login_url: String::new(),
+            tfa_url: String::new(),
+            mqtt_url: String::new(),
+            mqtt_mode: default_mqtt_mode(),
+            request_policy: BambuRequestPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct BambuEndpoints {
+    pub region: String,
+    pub api_url: String,
+    pub login_url: String,
+    pub tfa_url: String,
+    pub mqtt_url: String,
+}
+
+impl BambuEndpoints {
+    pub fn global() -> BambuEndpoints {
+        BambuEndpoints {
+            region: "global".to_string(),
+            api_url: constants::BAMBU_API_URL.to_string(),
+            login_url: constants::BAMBU_LOGIN_URL.to_string(),
+            tfa_url: constants::BAMBU_TFA_URL.to_string(),
+            mqtt_url: constants::BAMBU_MQTT_URL.to_string(),
+        }
+    }
+
+    pub fn china() -> BambuEndpoints {
+        BambuEndpoints {
+            region: "china".to_string(),
+            api_url: constants::BAMBU_CN_API_URL.to_string(),
+            login_url: constants::BAMBU_CN_LOGIN_URL.to_string(),
+            tfa_url: constants::BAMBU_CN_TFA_URL.to_string(),
+            mqtt_url: constants::BAMBU_CN_MQTT_URL.to_string(),
+        }
+    }
+}
+
+// What the MQTT client needs to reach devices through the cloud broker
+#[derive(Debug, Clone)]
+pub struct BambuCloudMqttCredentials {
+    pub broker_url: String,
+    pub uid: i64,
+    pub token: String,
+}
+
+fn validate_endpoint_url(name: &str, url: &str) -> Result<String, std::io::Error> {
+    let url = url.trim().trim_end_matches('/');
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Expected {} to be an http(s) URL, but got: {}", name, url),
+        ));
+    }
+
+    Ok(url.to_string())
+}
+
+fn validate_mqtt_url(url: &str) -> Result<String, std::io::Error> {
+    let url = url.trim().trim_end_matches('/');
+    if !["mqtt://", "mqtts://", "tcp://", "ssl://", "ws://", "wss://"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Expected mqtt_url to be an mqtt(s) or websocket URL, but got: {}",
+                url
+            ),
+        ));
+    }
+
+    Ok(url.to_string())
+}
+
+impl BambuCloudConfig {
+    pub fn validate_mqtt_mode(&self) -> Result<(), std::io::Error> {
+        if !["auto", "lan", "cloud"].contains(&self.mqtt_mode.as_str()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown MQTT mode: {}. Expected auto, lan or cloud.",
+                    self.mqtt_mode
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn endpoints(&self) -> Result<BambuEndpoints, std::io::Error> {
+        let mut endpoints = match self.region.as_str() {
+            "global" => BambuEndpoints::global(),
+            "china" => BambuEndpoints::china(),
+            "custom" => {
+                let api_url = validate_endpoint_url("api_url", &self.api_url)?;
+
+                // Anything left empty is assumed to live on the same server as the API
+                let login_url = match self.login_url.trim() {
+                    "" => format!("{}/api/sign-in/form", api_url),
+                    url => validate_endpoint_url("login_url", url)?,
+                };
+                let tfa_url = match self.tfa_url.trim() {
+                    "" => format!("{}/api/sign-in/tfa", api_url),
+                    url => validate_endpoint_url("tfa_url", url)?,
+                };
+
+                BambuEndpoints {
+                    region: "custom".to_string(),
+                    api_url,
+                    login_url,
+                    tfa_url,
+                    mqtt_url: constants::BAMBU_MQTT_URL.to_string(),
+                }
+            }
+            region => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Unknown cloud region: {}. Expected global, china or custom.",
+                        region
+                    ),
+                ))
+            }
+        };
+
+        if !self.mqtt_url.trim().is_empty() {
+            endpoints.mqtt_url = validate_mqtt_url(&self.mqtt_url)?;
+        }
+
+        Ok(endpoints)
+    }
 }
 
 pub struct BambuMQTTClient {
     watched_devices: Vec<(BambuDevice, paho_mqtt::AsyncClient)>,
     device_watch_threads: Vec<(BambuDevice, tokio::task::JoinHandle<()>)>,
     device_updater_thread: Option<tokio::task::JoinHandle<()>>,
+    device_reports: Arc<Mutex<HashMap<String, Value>>>,
+    job_tracker: Arc<Mutex<JobTracker>>,
+    job_events: broadcast::Sender<(BambuDevice, JobEvent)>,
+    upgrade_states: Arc<Mutex<HashMap<String, UpgradeState>>>,
+    upgrade_events: broadcast::Sender<(BambuDevice, UpgradeState)>,
     is_initialized: bool,
 }
 
@@ -25,7 +249,9 @@ pub struct BambuUserResponse {
     refresh_token: String,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+// Only exp is needed, the rest is optional so a changed token layout doesn't break decoding
+#[derive(Debug, serde::Deserialize, serde::Serialize, Default)]
+#[serde(default)]
 pub struct BambuUserJwt {
     exp: i64,
     iat: i64,
@@ -43,17 +269,93 @@ pub struct BambuUserJwt {
     username: String,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, serde::Deserialize, serde::Serialize, Default)]
 struct BambuUserRealmAccess {
     roles: Vec<String>,
 }
 
+// Serialized flat, so a completed login still looks like a BambuUserResponse to the frontend
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BambuLoginStep {
+    Complete(BambuUserResponse),
+    CodeRequired { login_type: String }, // verifyCode (emailed) or tfa (authenticator app)
+}
+
+#[derive(Debug, Clone)]
+struct PendingLogin {
+    account: String,
+    login_type: String,
+    tfa_key: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BambuRefreshResponse {
+    access_token: String,
+    refresh_token: String,
+    #[serde(default)]
+    expires_in: i64,
+    #[serde(default)]
+    refresh_expires_in: i64,
+}
+
+// Who the signed in account belongs to, cached in the config for features that need the uid
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+pub struct BambuAccountProfile {
+    pub uid: i64,
+    pub username: String,
+    pub name: String,
+    pub avatar: String,
+    pub region: String,
+}
+
+// A print from the account's cloud history. Bambu leaves fields out depending on where the
+// job came from (e.g. SD card prints have no design), so everything has a default
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(default)]
+pub struct BambuTask {
+    pub id: i64,
+    pub title: String,
+    #[serde(alias = "designTitle")]
+    pub design_title: String,
+    #[serde(alias = "deviceId")]
+    pub device_id: String,
+    #[serde(alias = "deviceName")]
+    pub device_name: String,
+    #[serde(alias = "deviceModel")]
+    pub device_model: String,
+    #[serde(alias = "startTime")]
+    pub start_time: String,
+    #[serde(alias = "endTime")]
+    pub end_time: String,
+    pub status: i64, // 1 running, 2 finished, 3 failed
+    pub weight: f64, // grams
+    pub length: f64, // millimetres of filament
+    #[serde(alias = "costTime")]
+    pub cost_time: i64, // seconds
+    #[serde(alias = "cover")]
+    pub cover_url: String,
+    #[serde(alias = "plateIndex")]
+    pub plate_index: i64,
+    #[serde(alias = "plateName")]
+    pub plate_name: String,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Default)]
+pub struct BambuTaskPage {
+    #[serde(default)]
+    pub total: i64,
+    #[serde(default, alias = "hits")]
+    pub tasks: Vec<BambuTask>,
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct BambuDeviceResponse {
     message: String,
     code: Option<i32>,
     error: Option<String>,
-    devices: Vec<BambuDevice>,
+    pub devices: Vec<BambuDevice>,
 }
 
 // Define the BambuDeviceResponse's format
@@ -67,6 +369,58 @@ impl std::fmt::Display for BambuDeviceResponse {
     }
 }
 
+// A firmware release offered by the cloud for a device or its AMS
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+pub struct BambuFirmwareRelease {
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub force_update: bool,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub status: String,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+pub struct BambuAmsFirmware {
+    #[serde(default)]
+    pub address: Option<u32>, // The unit's index, ams/<address> in info.get_version
+    #[serde(default)]
+    pub dev_model_name: String,
+    #[serde(default)]
+    pub firmware: Vec<BambuFirmwareRelease>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Default)]
+pub struct BambuDeviceFirmware {
+    #[serde(default)]
+    pub dev_id: String,
+    #[serde(default)]
+    pub firmware: Vec<BambuFirmwareRelease>,
+    #[serde(default)]
+    pub ams: Vec<BambuAmsFirmware>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BambuFirmwareVersionResponse {
+    #[serde(default)]
+    devices: Vec<BambuDeviceFirmware>,
+}
+
+// Device actions answer with a message and an error instead of a failing status code
+#[derive(Debug, serde::Deserialize)]
+struct BambuActionResponse {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    code: Option<i32>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct BambuDevice {
     pub dev_id: String,
@@ -78,33 +432,364 @@ pub struct BambuDevice {
     pub dev_product_name: String,
     pub dev_access_code: String,
     pub nozzle_diameter: Number,
+    #[serde(default)]
+    pub removed: bool, // Set by the device sync when the printer is no longer bound to the account
 }
 
+// Everything that can go wrong talking to the cloud API. Serialized with a kind so
+// the frontend can react, e.g. send the user back to sign in on unauthorized
 #[derive(Debug)]
-pub enum BambuLoginError {
-    ReqwestError(reqwest::Error),
-    IoError(std::io::Error),
+pub enum BambuApiError {
+    Network(String),
+    Unauthorized(String), // 401, or the session could no longer be refreshed
+    InvalidCredentials {
+        // Login form rejected, e.g. a wrong password or an expired code
+        message: String,
+        status: u16,
+        body: String,
+    },
+    Forbidden(String),
+    RateLimited {
+        retry_after: Option<u64>,
+        body: String,
+    },
+    Server {
+        status: u16,
+        body: String,
+    },
+    Http {
+        status: u16,
+        body: String,
+    },
+    Schema {
+        message: String,
+        body: String,
+    },
+    Cancelled,     // The frontend gave up on the request, e.g. the user navigated away
+    Other(String), // Local failures, e.g. no token set or the config couldn't be written
+}
+
+impl BambuApiError {
+    pub fn from_status(status: u16, retry_after: Option<u64>, body: String) -> BambuApiError {
+        match status {
+            401 => BambuApiError::Unauthorized(body),
+            403 => BambuApiError::Forbidden(body),
+            429 => BambuApiError::RateLimited { retry_after, body },
+            500..=599 => BambuApiError::Server { status, body },
+            _ => BambuApiError::Http { status, body },
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BambuApiError::Network(_) => "network",
+            BambuApiError::Unauthorized(_) => "unauthorized",
+            BambuApiError::InvalidCredentials { .. } => "invalid_credentials",
+            BambuApiError::Forbidden(_) => "forbidden",
+            BambuApiError::RateLimited { .. } => "rate_limited",
+            BambuApiError::Server { .. } => "server",
+            BambuApiError::Http { .. } => "http",
+            BambuApiError::Schema { .. } => "schema",
+            BambuApiError::Cancelled => "cancelled",
+            BambuApiError::Other(_) => "other",
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            BambuApiError::Unauthorized(_) => Some(401),
+            BambuApiError::Forbidden(_) => Some(403),
+            BambuApiError::RateLimited { .. } => Some(429),
+            BambuApiError::InvalidCredentials { status, .. }
+            | BambuApiError::Server { status, .. }
+            | BambuApiError::Http { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    pub fn body(&self) -> Option<&str> {
+        match self {
+            BambuApiError::Unauthorized(body) | BambuApiError::Forbidden(body) => Some(body),
+            BambuApiError::InvalidCredentials { body, .. }
+            | BambuApiError::RateLimited { body, .. }
+            | BambuApiError::Server { body, .. }
+            | BambuApiError::Http { body, .. }
+            | BambuApiError::Schema { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, BambuApiError::Unauthorized(_))
+    }
+
+    // The login endpoints answer 400 or 401 for what the user typed, which isn't an expired session
+    fn rejected_credentials(self, message: &str) -> BambuApiError {
+        match self {
+            BambuApiError::Unauthorized(body) => BambuApiError::InvalidCredentials {
+                message: message.to_string(),
+                status: 401,
+                body,
+            },
+            BambuApiError::Http { status: 400, body } => BambuApiError::InvalidCredentials {
+                message: message.to_string(),
+                status: 400,
+                body,
+            },
+            e => e,
+        }
+    }
 }
 
-impl std::fmt::Display for BambuLoginError {
+impl std::fmt::Display for BambuApiError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            BambuLoginError::ReqwestError(e) => write!(f, "ReqwestError: {}", e),
-            BambuLoginError::IoError(e) => write!(f, "IoError: {}", e),
+            BambuApiError::Network(e) => write!(f, "Could not reach Bambu: {}", e),
+            BambuApiError::Unauthorized(_) => {
+                write!(f, "Session expired, please sign in again.")
+            }
+            BambuApiError::InvalidCredentials { message, .. } => write!(f, "{}", message),
+            BambuApiError::Forbidden(_) => {
+                write!(f, "Bambu refused access to this resource.")
+            }
+            BambuApiError::RateLimited { retry_after, .. } => match retry_after {
+                Some(secs) => write!(f, "Rate limited by Bambu, try again in {}s.", secs),
+                None => write!(f, "Rate limited by Bambu, try again later."),
+            },
+            BambuApiError::Server { status, .. } => {
+                write!(f, "Bambu had a server error (status code {}).", status)
+            }
+            BambuApiError::Http { status, body } => {
+                write!(f, "Bambu returned status code {}: {}", status, body)
+            }
+            BambuApiError::Schema { message, .. } => {
+                write!(f, "Unexpected response from Bambu: {}", message)
+            }
+            BambuApiError::Cancelled => write!(f, "The request was cancelled."),
+            BambuApiError::Other(e) => write!(f, "{}", e),
         }
     }
 }
 
+impl serde::Serialize for BambuApiError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let retry_after = match self {
+            BambuApiError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        };
+
+        let mut state = serializer.serialize_struct("BambuApiError", 5)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("status", &self.status())?;
+        state.serialize_field("retry_after", &retry_after)?;
+        state.serialize_field("body", &self.body())?;
+        state.end()
+    }
+}
+
+impl From<reqwest::Error> for BambuApiError {
+    fn from(e: reqwest::Error) -> Self {
+        BambuApiError::Network(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for BambuApiError {
+    fn from(e: std::io::Error) -> Self {
+        BambuApiError::Other(e.to_string())
+    }
+}
+
+// Only the delay in seconds form is used by Bambu, not HTTP dates
+fn retry_after_secs(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+}
+
+fn build_http_client(
+    policy: &BambuRequestPolicy,
+    network: &NetworkSettings,
+) -> Result<reqwest::Client, BambuApiError> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(policy.connect_timeout_secs.max(1)))
+        .timeout(Duration::from_secs(policy.request_timeout_secs.max(1)));
+
+    if let Some(proxy) = network.reqwest_proxy()? {
+        builder = builder.proxy(proxy);
+    }
+
+    // Added on top of the system roots, not instead of them
+    for certificate in network.reqwest_certificates()? {
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    builder
+        .build()
+        .map_err(|e| BambuApiError::Other(format!("Could not create the HTTP client: {}", e)))
+}
+
+fn load_request_policy() -> BambuRequestPolicy {
+    get_config_path()
+        .and_then(|path| Config::load_or_create(&path))
+        .map(|config| config.cloud.request_policy)
+        .unwrap_or_default()
+}
+
+// Pass successful responses through, turn the rest into the matching error with the body kept
+async fn check_response(response: reqwest::Response) -> Result<reqwest::Response, BambuApiError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = retry_after_secs(&response);
+    let body = response.text().await.unwrap_or("".to_string());
+
+    Err(BambuApiError::from_status(
+        status.as_u16(),
+        retry_after,
+        body,
+    ))
+}
+
+fn parse_response<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, BambuApiError> {
+    serde_json::from_str(body).map_err(|e| BambuApiError::Schema {
+        message: e.to_string(),
+        body: body.to_string(),
+    })
+}
+
+async fn check_action_response(response: reqwest::Response) -> Result<(), BambuApiError> {
+    let response = check_response(response).await?;
+    let body = response.text().await?;
+    let action: BambuActionResponse = parse_response(&body)?;
+
+    match action.error.filter(|e| !e.is_empty()) {
+        Some(error) => Err(BambuApiError::Other(error)),
+        None if action.code.unwrap_or(0) != 0 => Err(BambuApiError::Other(format!(
+            "Request failed with code {}: {}",
+            action.code.unwrap_or(0),
+            action.message
+        ))),
+        None => Ok(()),
+    }
+}
+
 impl BambuMQTTClient {
     pub fn new() -> BambuMQTTClient {
         BambuMQTTClient {
             watched_devices: vec![],
             device_watch_threads: vec![],
             device_updater_thread: None,
+            device_reports: Arc::new(Mutex::new(HashMap::new())),
+            job_tracker: Arc::new(Mutex::new(JobTracker::new())),
+            job_events: broadcast::channel(64).0,
+            upgrade_states: Arc::new(Mutex::new(HashMap::new())),
+            upgrade_events: broadcast::channel(64).0,
             is_initialized: false,
         }
     }
 
+    // Receive job started/progress/HMS/finished events from all watched devices
+    pub fn subscribe_job_events(&self) -> broadcast::Receiver<(BambuDevice, JobEvent)> {
+        self.job_events.subscribe()
+    }
+
+    // Receive upgrade_state changes from all watched devices
+    pub fn subscribe_upgrade_events(&self) -> broadcast::Receiver<(BambuDevice, UpgradeState)> {
+        self.upgrade_events.subscribe()
+    }
+
+    pub async fn get_upgrade_state(&self, dev_id: &str) -> Option<UpgradeState> {
+        self.upgrade_states.lock().await.get(dev_id).cloned()
+    }
+
+    pub fn get_watched_device(&self, dev_id: &str) -> Option<BambuDevice> {
+        self.watched_devices
+            .iter()
+            .find(|(d, _)| d.dev_id == dev_id)
+            .map(|(d, _)| d.clone())
+    }
+
+    // Publish a command to a watched device's request topic
+    pub async fn send_command(&self, dev_id: &str, payload: Value) -> Result<(), std::io::Error> {
+        let (device, client) = self
+            .watched_devices
+            .iter()
+            .find(|(d, _)| d.dev_id == dev_id)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "Device: {} is not being watched, so commands can't be sent to it.",
+                        dev_id
+                    ),
+                )
+            })?;
+
+        if blocked_during_upgrade(&payload) {
+            if let Some(upgrade) = self.get_upgrade_state(dev_id).await {
+                if upgrade.in_progress() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!(
+                            "Device: {} is updating its firmware ({}, {}%), please wait for it to finish.",
+                            device.name, upgrade.status, upgrade.progress
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let request_msg = paho_mqtt::Message::new(
+            format!("device/{}/request", device.dev_id),
+            payload.to_string().as_bytes(),
+            1,
+        );
+
+        client
+            .publish(request_msg)
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "Failed to publish command for device: {}: {}",
+                        device.name, e
+                    ),
+                )
+            })
+            .await
+    }
+
+    // Ask a device for its module versions. The answer arrives as a report, so the last one
+    // is cleared first to tell a fresh answer apart
+    pub async fn request_versions(&self, dev_id: &str) -> Result<(), std::io::Error> {
+        if let Some(Value::Object(report)) = self.device_reports.lock().await.get_mut(dev_id) {
+            report.remove("info");
+        }
+
+        self.send_command(
+            dev_id,
+            json!({
+                "info": {
+                    "sequence_id": "0",
+                    "command": "get_version"
+                }
+            }),
+        )
+        .await
+    }
+
+    // Get the latest known state of a watched device, merged from all reports received so far
+    pub async fn get_device_report(&self, dev_id: &str) -> Option<Value> {
+        self.device_reports.lock().await.get(dev_id).cloned()
+    }
+
     pub async fn initialize(&mut self) {
         if self.is_initialized {
             return;
@@ -221,7 +906,64 @@ impl BambuMQTTClient {
         self.is_initialized = false;
     }
 
-    pub async fn watch_device(&mut self, device: BambuDevice) -> Result<(), std::io::Error> {
+    // Connect with retries, the printers can take a moment to accept connections after waking up
+    async fn connect_with_retries(
+        server_uri: &str,
+        client_id: &str,
+        connection_opts: paho_mqtt::ConnectOptions,
+        device: &BambuDevice,
+        attempts: u32,
+    ) -> Result<paho_mqtt::AsyncClient, std::io::Error> {
+        let create_opts = paho_mqtt::CreateOptionsBuilder::new()
+            .server_uri(server_uri)
+            .client_id(client_id)
+            .finalize();
+
+        let client = paho_mqtt::AsyncClient::new(create_opts).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Failed to create MQTT client: {}", e),
+            )
+        })?;
+
+        for i in 0..attempts {
+            match client.connect(connection_opts.clone()).await {
+                Ok(_) => {
+                    println!(
+                        "[BambuMQTTClient::connect_with_retries] Successfully connected to MQTT broker at {} for device: {}",
+                        server_uri, device.name
+                    );
+
+                    return Ok(client);
+                }
+                Err(e) => {
+                    println!(
+                        "[BambuMQTTClient::connect_with_retries] Failed to connect to MQTT broker at {}: {} for device: {}. (Attempt {} of {})",
+                        server_uri, e, device.name, i + 1, attempts
+                    );
+                }
+            }
+
+            // Sleep for 5 seconds before retrying
+            if i + 1 < attempts {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!(
+                "Failed to connect to MQTT broker at {} for device: {} after {} attempts",
+                server_uri, device.name, attempts
+            ),
+        ))
+    }
+
+    // A probe is a single short attempt, for when the cloud broker is there to fall back on
+    async fn connect_lan(
+        device: &BambuDevice,
+        probe: bool,
+    ) -> Result<paho_mqtt::AsyncClient, std::io::Error> {
         let device_ip = match &device.ip {
             Some(ip) => ip,
             None => {
@@ -236,58 +978,156 @@ impl BambuMQTTClient {
         };
 
         let ssl_options = paho_mqtt::SslOptions::new();
-
-        let connection_opts = paho_mqtt::ConnectOptionsBuilder::new()
+        let mut connection_opts = paho_mqtt::ConnectOptionsBuilder::new();
+        connection_opts
             .user_name("bblp")
             .password(device.dev_access_code.clone())
             .ssl_options(ssl_options)
-            .keep_alive_interval(std::time::Duration::from_secs(30))
-            .finalize();
+            .keep_alive_interval(std::time::Duration::from_secs(30));
 
-        let client =
-            paho_mqtt::AsyncClient::new(format!("mqtts://{}:8883", device_ip)).map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Failed to create MQTT client: {}", e),
-                )
-            })?;
+        if probe {
+            connection_opts.connect_timeout(LAN_PROBE_TIMEOUT);
+        }
+
+        Self::connect_with_retries(
+            &format!("mqtts://{}:8883", device_ip),
+            "",
+            connection_opts.finalize(),
+            device,
+            if probe { 1 } else { 3 },
+        )
+        .await
+    }
 
-        let mut connected = false;
+    async fn connect_cloud(
+        device: &BambuDevice,
+        cloud: &BambuCloudMqttCredentials,
+    ) -> Result<paho_mqtt::AsyncClient, std::io::Error> {
+        let mut connection_opts = paho_mqtt::ConnectOptionsBuilder::new();
+        connection_opts
+            .user_name(format!("u_{}", cloud.uid))
+            .password(cloud.token.clone())
+            .keep_alive_interval(std::time::Duration::from_secs(30));
 
-        // Attempt to connect to the MQTT broker 3 times
-        for i in 0..3 {
-            match client.connect(connection_opts.clone()).await {
-                Ok(_) => {
-                    println!(
-                        "[BambuMQTTClient::watch_device] Successfully connected to MQTT broker at {} for device: {}",
-                        device_ip, device.name
-                    );
+        let network = load_network_settings();
 
-                    connected = true;
-                    break;
-                }
-                Err(e) => {
-                    println!(
-                        "[BambuMQTTClient::watch_device] Failed to connect to MQTT broker at {}: {} for device: {}. (Attempt {} of 3) Retrying in 5 seconds ...",
-                        device_ip, e, device.name, i + 1
-                    );
-                }
+        // Plain mqtt:// is only for testing against a local broker
+        if ["mqtts://", "ssl://", "wss://"]
+            .iter()
+            .any(|scheme| cloud.broker_url.starts_with(scheme))
+        {
+            let mut ssl_options = paho_mqtt::SslOptionsBuilder::new();
+            if let Some(trust_store) = network.mqtt_trust_store()? {
+                ssl_options.trust_store(trust_store).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("Failed to use the extra CA certificates: {}", e),
+                    )
+                })?;
             }
-
-            // Sleep for 5 seconds before retrying
-            tokio::time::sleep(Duration::from_secs(5)).await;
+            connection_opts.ssl_options(ssl_options.finalize());
         }
 
-        if !connected {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::TimedOut,
-                format!(
-                    "Failed to connect to MQTT broker at {} for device: {} after 3 attempts",
-                    device_ip, device.name
-                ),
-            ));
+        // paho can only tunnel websocket connections through a proxy. Networks that need
+        // a proxy rarely allow direct connections, so don't try one behind the user's back
+        if let Some(proxy_url) = network.mqtt_proxy_url()? {
+            if !(cloud.broker_url.starts_with("ws://") || cloud.broker_url.starts_with("wss://")) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "A proxy is configured, but the MQTT broker {} can only be reached through one over websockets. Set a ws:// or wss:// MQTT URL, or use LAN mode.",
+                        cloud.broker_url
+                    ),
+                ));
+            }
+
+            connection_opts
+                .http_proxy(proxy_url.clone())
+                .https_proxy(proxy_url);
         }
 
+        // The cloud broker drops connections that share a client id
+        let client_id = format!(
+            "bambuconnect_{}_{}",
+            device.dev_id,
+            chrono::Local::now().timestamp_millis()
+        );
+
+        Self::connect_with_retries(
+            &cloud.broker_url,
+            &client_id,
+            connection_opts.finalize(),
+            device,
+            3,
+        )
+        .await
+    }
+
+    // Watch a device over LAN when we can reach it, otherwise through the cloud broker.
+    // mode is auto, lan or cloud, see BambuCloudConfig
+    pub async fn watch_device(
+        &mut self,
+        device: BambuDevice,
+        mode: &str,
+        cloud: Option<BambuCloudMqttCredentials>,
+    ) -> Result<(), std::io::Error> {
+        let connection = Self::connect(&device, mode, cloud).await?;
+        self.watch_connected(device, connection).await
+    }
+
+    // The connecting half of watch_device, which can take a while with retries. It doesn't
+    // need the client, so callers can connect first and only lock the client to watch
+    pub async fn connect(
+        device: &BambuDevice,
+        mode: &str,
+        cloud: Option<BambuCloudMqttCredentials>,
+    ) -> Result<(paho_mqtt::AsyncClient, &'static str), std::io::Error> {
+        let lan_result = if mode != "cloud" && device.ip.is_some() {
+            let probe = mode == "auto" && cloud.is_some();
+            Some(Self::connect_lan(device, probe).await)
+        } else {
+            None
+        };
+
+        let (client, transport) = match (lan_result, cloud) {
+            (Some(Ok(client)), _) => (client, "lan"),
+            (Some(Err(e)), _) if mode == "lan" => return Err(e),
+            (lan_result, Some(cloud)) => {
+                if let Some(Err(e)) = lan_result {
+                    println!(
+                        "[BambuMQTTClient::connect] LAN connection to device: {} failed, falling back to cloud: {}",
+                        device.name, e
+                    );
+                }
+
+                (Self::connect_cloud(device, &cloud).await?, "cloud")
+            }
+            (Some(Err(e)), None) => return Err(e),
+            (None, None) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "Device: {} has no IP address and no cloud credentials are available to reach it.",
+                        device.name
+                    ),
+                ));
+            }
+        };
+
+        Ok((client, transport))
+    }
+
+    // Subscribe to a device over a connection from connect and follow its reports
+    pub async fn watch_connected(
+        &mut self,
+        device: BambuDevice,
+        (client, transport): (paho_mqtt::AsyncClient, &'static str),
+    ) -> Result<(), std::io::Error> {
+        println!(
+            "[BambuMQTTClient::watch_connected] Watching device: {} over {}",
+            device.name, transport
+        );
+
         // Clone the client for use in the closure
         let mut client_clone = client.clone();
         let device_clone = device.clone();
@@ -295,6 +1135,11 @@ impl BambuMQTTClient {
         // Create yet another clone of the device to pass into vec
         // This is utterly ridiculous, but it's the only way to get the device into the vec without rust bitching
         let device_vec_clone = device.clone();
+        let device_reports = self.device_reports.clone();
+        let job_tracker = self.job_tracker.clone();
+        let job_events = self.job_events.clone();
+        let upgrade_states = self.upgrade_states.clone();
+        let upgrade_events = self.upgrade_events.clone();
 
         // Subscribe to the device's status topic
         let status_topic = format!("device/{}/report", device.dev_id);
@@ -372,6 +1217,55 @@ impl BambuMQTTClient {
                             "[BambuMQTTClient::task::device_watch] Received message from device: {}: {}",
                             device_clone.name, msg.payload_str()
                         );
+
+                        match serde_json::from_slice::<Value>(msg.payload()) {
+                            Ok(update) => {
+                                let (observation, upgrade) = {
+                                    let mut reports = device_reports.lock().await;
+                                    let report = reports
+                                        .entry(device_clone.dev_id.clone())
+                                        .or_insert_with(|| json!({}));
+
+                                    merge_report(report, update);
+                                    let observation = job_tracker
+                                        .lock()
+                                        .await
+                                        .observe(&device_clone.dev_id, report);
+
+                                    (observation, UpgradeState::from_report(report))
+                                };
+
+                                // Save before telling anyone, so listeners find the job in the log
+                                save_records(observation.records).await;
+
+                                // Nobody listening is fine, sending only fails when there are no receivers
+                                for event in observation.events {
+                                    let _ = job_events.send((device_clone.clone(), event));
+                                }
+
+                                if let Some(upgrade) = upgrade {
+                                    let mut states = upgrade_states.lock().await;
+                                    let previous =
+                                        states.insert(device_clone.dev_id.clone(), upgrade.clone());
+
+                                    // An idle printer reports IDLE on connect, only changes are interesting
+                                    let changed = match previous {
+                                        Some(previous) => previous != upgrade,
+                                        None => upgrade.in_progress(),
+                                    };
+                                    if changed {
+                                        let _ =
+                                            upgrade_events.send((device_clone.clone(), upgrade));
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                println!(
+                                    "[BambuMQTTClient::task::device_watch] Failed to parse message from device: {}: {}",
+                                    device_clone.name, e
+                                );
+                            }
+                        }
                     }
                     Err(e) => {
                         println!(
@@ -392,19 +1286,6 @@ impl BambuMQTTClient {
     }
 
     pub async fn unwatch_device(&mut self, device: BambuDevice) -> Result<(), std::io::Error> {
-        let device_ip = match &device.ip {
-            Some(ip) => ip,
-            None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!(
-                        "Expected device: {} to have an IP address, but none was found.",
-                        device.name
-                    ),
-                ));
-            }
-        };
-
         // Find the device in the watched devices
         let device_index = self
             .watched_devices
@@ -419,8 +1300,8 @@ impl BambuMQTTClient {
                 std::io::Error::new(
                     std::io::ErrorKind::Other,
                     format!(
-                        "Failed to disconnect from MQTT broker at {} for device: {}: {}",
-                        device_ip, device.name, e
+                        "Failed to disconnect from MQTT broker for device: {}: {}",
+                        device.name, e
                     ),
                 )
             })?;
@@ -428,6 +1309,9 @@ impl BambuMQTTClient {
             // Kill the thread and remove it from the list
             let (_, handle) = self.device_watch_threads.remove(index);
             handle.abort();
+
+            self.device_reports.lock().await.remove(&device.dev_id);
+            self.upgrade_states.lock().await.remove(&device.dev_id);
         } else {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
@@ -454,11 +1338,309 @@ impl BambuMQTTClient {
     }
 }
 
+// P1 and A1 series only send the fields that changed, so reports are merged into the last known state
+fn merge_report(target: &mut Value, update: Value) {
+    match (target, update) {
+        (Value::Object(target), Value::Object(update)) => {
+            for (key, value) in update {
+                merge_report(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, update) => *target = update,
+    }
+}
+
+// Read the expiry from a jwt. The signature can't be checked without Bambu's key,
+// but we only use this to decide when to refresh, the API still validates the token
+pub fn decode_jwt(jwt: &str) -> Result<BambuUserJwt, std::io::Error> {
+    let header = jsonwebtoken::decode_header(jwt).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Failed to decode jwt header: {}", e),
+        )
+    })?;
+
+    let mut validation = jsonwebtoken::Validation::new(header.alg);
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+
+    jsonwebtoken::decode::<BambuUserJwt>(
+        jwt,
+        &jsonwebtoken::DecodingKey::from_secret(&[]),
+        &validation,
+    )
+    .map(|data| data.claims)
+    .map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Failed to decode jwt: {}", e),
+        )
+    })
+}
+
+fn jwt_expiry(jwt: &str) -> Option<i64> {
+    match decode_jwt(jwt) {
+        Ok(claims) if claims.exp > 0 => Some(claims.exp),
+        Ok(_) => None,
+        Err(e) => {
+            println!("[BambuClient::jwt_expiry] Could not read jwt expiry: {}", e);
+            None
+        }
+    }
+}
+
+// Endpoints configured when the client is first used, save_config updates them afterwards
+fn load_endpoints() -> BambuEndpoints {
+    let endpoints = get_config_path()
+        .and_then(|path| Config::load_or_create(&path))
+        .and_then(|config| config.cloud.endpoints());
+
+    match endpoints {
+        Ok(endpoints) => endpoints,
+        Err(e) => {
+            println!(
+                "[BambuClient::load_endpoints] Failed to load cloud endpoints, using global: {}",
+                e
+            );
+            BambuEndpoints::global()
+        }
+    }
+}
+
+// Fill in the IP of every device that announced itself, dropping the ones that didn't
+pub fn match_announcements(
+    devices: Vec<BambuDevice>,
+    announcements: &[BambuAnnouncement],
+) -> Vec<BambuDevice> {
+    devices
+        .into_iter()
+        .filter_map(|mut device| {
+            let announcement = announcements.iter().find(|a| a.dev_id == device.dev_id)?;
+            device.ip = Some(announcement.ip.clone());
+            Some(device)
+        })
+        .collect()
+}
+
+// Bambu's web login endpoints hand out tokens as cookies
+fn tokens_from_cookies(response: &reqwest::Response) -> BambuUserResponse {
+    // Get all set-cookies headers
+    let cookies = response
+        .headers()
+        .get_all(reqwest::header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(|cookie| cookie.trim())
+        .filter(|cookie| !cookie.is_empty());
+
+    let mut user_response = BambuUserResponse {
+        token: String::new(),
+        refresh_token: String::new(),
+    };
+
+    for cookie in cookies {
+        if cookie.starts_with("token=") {
+            user_response.token = cookie.split('=').collect::<Vec<&str>>()[1].to_string();
+        } else if cookie.starts_with("refreshToken=") {
+            user_response.refresh_token = cookie.split('=').collect::<Vec<&str>>()[1].to_string();
+        }
+    }
+
+    user_response
+}
+
+// Store a refreshed token pair so it survives restarts
+fn persist_session(
+    jwt: &str,
+    refresh_token: &str,
+    jwt_expires_at: i64,
+    refresh_token_expires_at: i64,
+) -> Result<(), std::io::Error> {
+    update_config(|config| {
+        config.bambu_info.jwt = jwt.to_string();
+        config.bambu_info.refresh_token = refresh_token.to_string();
+        config.bambu_info.jwt_expires_at = jwt_expires_at;
+        config.bambu_info.refresh_token_expires_at = refresh_token_expires_at;
+        config.bambu_info.jwt_last_refresh = chrono::Local::now().timestamp();
+        Ok(())
+    })
+}
+
+// A frontend call registered with track_request, cancel_request can abort it until it's dropped
+pub struct CancelToken<'a> {
+    client: &'a BambuClient,
+    id: String,
+    cancelled: watch::Receiver<bool>,
+}
+
+impl CancelToken<'_> {
+    // Resolves once the call is cancelled, never without a token
+    async fn wait(cancelled: &mut Option<watch::Receiver<bool>>) {
+        if let Some(cancelled) = cancelled {
+            if cancelled.wait_for(|c| *c).await.is_ok() {
+                return;
+            }
+        }
+
+        std::future::pending().await
+    }
+}
+
+impl Drop for CancelToken<'_> {
+    fn drop(&mut self) {
+        self.client.cancellable.lock().unwrap().remove(&self.id);
+    }
+}
+
 impl BambuClient {
     pub fn new() -> BambuClient {
+        let request_policy = load_request_policy();
+        let client =
+            build_http_client(&request_policy, &load_network_settings()).unwrap_or_else(|e| {
+                println!("[BambuClient::new] {}, using the defaults", e);
+                reqwest::Client::new()
+            });
+
         BambuClient {
-            client: reqwest::Client::new(),
+            client: RwLock::new(client),
+            request_policy: RwLock::new(request_policy),
+            cancellable: std::sync::Mutex::new(HashMap::new()),
             jwt: Mutex::new(None),
+            refreshing: Mutex::new(()),
+            jwt_expires_at: Mutex::new(None),
+            refresh_token: Mutex::new(None),
+            pending_login: Mutex::new(None),
+            endpoints: RwLock::new(load_endpoints()),
+        }
+    }
+
+    pub fn endpoints(&self) -> BambuEndpoints {
+        self.endpoints.read().unwrap().clone()
+    }
+
+    pub fn set_endpoints(&self, endpoints: BambuEndpoints) {
+        println!(
+            "[BambuClient::set_endpoints] Using cloud endpoints: {:?}",
+            endpoints
+        );
+        *self.endpoints.write().unwrap() = endpoints;
+    }
+
+    pub fn set_http_settings(
+        &self,
+        policy: BambuRequestPolicy,
+        network: &NetworkSettings,
+    ) -> Result<(), BambuApiError> {
+        let client = build_http_client(&policy, network)?;
+
+        println!(
+            "[BambuClient::set_http_settings] Using request policy: {:?}, proxy: {}, extra CA files: {}",
+            policy,
+            network.proxy.as_ref().map(|p| p.url.as_str()).unwrap_or("none"),
+            network.extra_ca_certs.len()
+        );
+        *self.client.write().unwrap() = client;
+        *self.request_policy.write().unwrap() = policy;
+        Ok(())
+    }
+
+    fn http(&self) -> reqwest::Client {
+        self.client.read().unwrap().clone()
+    }
+
+    // Let the frontend cancel a call by the id it passed in. Calls without an id, like the
+    // ones background tasks make, can't be cancelled
+    pub fn track_request(&self, request_id: Option<String>) -> Option<CancelToken<'_>> {
+        let id = request_id?;
+        let (sender, cancelled) = watch::channel(false);
+        self.cancellable.lock().unwrap().insert(id.clone(), sender);
+
+        Some(CancelToken {
+            client: self,
+            id,
+            cancelled,
+        })
+    }
+
+    // The call fails with BambuApiError::Cancelled, false if it already finished
+    pub fn cancel_request(&self, request_id: &str) -> bool {
+        match self.cancellable.lock().unwrap().get(request_id) {
+            Some(sender) => sender.send(true).is_ok(),
+            None => false,
+        }
+    }
+
+    // Send a request under the request policy. Rate limited requests are retried after
+    // Retry-After, the rest only when repeating them is safe: idempotent methods, or
+    // connections that failed before anything was sent
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+        cancel: Option<&CancelToken<'_>>,
+    ) -> Result<reqwest::Response, BambuApiError> {
+        let request = request.build()?;
+        let policy = self.request_policy.read().unwrap().clone();
+        let idempotent = matches!(
+            *request.method(),
+            reqwest::Method::GET
+                | reqwest::Method::HEAD
+                | reqwest::Method::PUT
+                | reqwest::Method::DELETE
+        );
+
+        let mut cancelled = cancel.map(|c| c.cancelled.clone());
+        let mut attempt = 0;
+
+        loop {
+            let attempt_request = request.try_clone().ok_or_else(|| {
+                BambuApiError::Other("Request body can't be sent more than once".to_string())
+            })?;
+
+            let result = tokio::select! {
+                result = self.http().execute(attempt_request) => result,
+                _ = CancelToken::wait(&mut cancelled) => return Err(BambuApiError::Cancelled),
+            };
+
+            let retry_in = match &result {
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    match retry_after_secs(response) {
+                        Some(secs) if secs > policy.max_retry_after_secs => None,
+                        Some(secs) => Some(Duration::from_secs(secs)),
+                        None => Some(policy.backoff(attempt)),
+                    }
+                }
+                Ok(response) if response.status().is_server_error() && idempotent => {
+                    Some(policy.backoff(attempt))
+                }
+                Ok(_) => None,
+                Err(e) if idempotent || e.is_connect() => Some(policy.backoff(attempt)),
+                Err(_) => None,
+            };
+
+            match retry_in {
+                Some(delay) if attempt < policy.max_retries => {
+                    println!(
+                        "[BambuClient::send] {} {} failed ({}), retrying in {:?}",
+                        request.method(),
+                        request.url().path(),
+                        match &result {
+                            Ok(response) => response.status().to_string(),
+                            Err(e) => e.to_string(),
+                        },
+                        delay
+                    );
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        _ = CancelToken::wait(&mut cancelled) => return Err(BambuApiError::Cancelled),
+                    }
+                    attempt += 1;
+                }
+                _ => return Ok(result?),
+            }
         }
     }
 
@@ -468,14 +1650,127 @@ impl BambuClient {
     }
 
     pub async fn set_jwt(&self, jwt: String) {
+        *self.jwt_expires_at.lock().await = jwt_expiry(&jwt);
         *self.jwt.lock().await = Some(jwt);
     }
 
+    pub async fn get_jwt_expires_at(&self) -> Option<i64> {
+        *self.jwt_expires_at.lock().await
+    }
+
+    // Exchange the refresh token for a new token pair and save it to the config
+    pub async fn refresh_jwt(&self) -> Result<(), BambuApiError> {
+        let stale_jwt = self.get_jwt().await;
+        let _refreshing = self.refreshing.lock().await;
+
+        // Another request refreshed while we waited, its tokens are the ones to use
+        if stale_jwt.is_some() && self.get_jwt().await != stale_jwt {
+            return Ok(());
+        }
+
+        // The setup page saves the refresh token after logging in, so fall back to the config
+        let refresh_token = match self.refresh_token.lock().await.clone() {
+            Some(refresh_token) if !refresh_token.is_empty() => refresh_token,
+            _ => {
+                Config::load_or_create(&get_config_path()?)?
+                    .bambu_info
+                    .refresh_token
+            }
+        };
+
+        if refresh_token.is_empty() {
+            return Err(BambuApiError::Unauthorized(
+                "No refresh token is available".to_string(),
+            ));
+        }
+
+        let request = self
+            .http()
+            .post(format!(
+                "{}/v1/user-service/user/refreshtoken",
+                self.endpoints().api_url
+            ))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json!({ "refreshToken": refresh_token }).to_string());
+
+        // Never cancelled, abandoning a refresh halfway could lose the new token pair
+        let response = self.send(request, None).await?;
+
+        // A rejected refresh token means the user has to sign in again
+        let response = match check_response(response).await {
+            Ok(response) => response,
+            Err(BambuApiError::Http { status, body }) if (400..500).contains(&status) => {
+                return Err(BambuApiError::Unauthorized(body));
+            }
+            Err(BambuApiError::Forbidden(body)) => return Err(BambuApiError::Unauthorized(body)),
+            Err(e) => return Err(e),
+        };
+
+        let response_text = response.text().await?;
+        let refreshed: BambuRefreshResponse = parse_response(&response_text)?;
+
+        let now = chrono::Local::now().timestamp();
+        let jwt_expires_at =
+            jwt_expiry(&refreshed.access_token).unwrap_or(now + refreshed.expires_in);
+        let refresh_token_expires_at = if refreshed.refresh_expires_in > 0 {
+            now + refreshed.refresh_expires_in
+        } else {
+            0
+        };
+
+        persist_session(
+            &refreshed.access_token,
+            &refreshed.refresh_token,
+            jwt_expires_at,
+            refresh_token_expires_at,
+        )?;
+
+        *self.jwt.lock().await = Some(refreshed.access_token);
+        *self.jwt_expires_at.lock().await = Some(jwt_expires_at);
+        *self.refresh_token.lock().await = Some(refreshed.refresh_token);
+
+        println!(
+            "[BambuClient::refresh_jwt] Refreshed jwt, it now expires at {}",
+            jwt_expires_at
+        );
+
+        Ok(())
+    }
+
+    // Refresh the jwt if it is about to expire. Fails with Unauthorized once the
+    // session can't be recovered without signing in again
+    pub async fn ensure_valid_jwt(&self) -> Result<(), BambuApiError> {
+        let expires_at = match self.get_jwt_expires_at().await {
+            Some(expires_at) => expires_at,
+            None => return Ok(()), // Nothing to go on, let the API decide
+        };
+
+        let now = chrono::Local::now().timestamp();
+        if now + JWT_REFRESH_MARGIN_SECS < expires_at {
+            return Ok(());
+        }
+
+        match self.refresh_jwt().await {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_unauthorized() => Err(e),
+            Err(e) if now >= expires_at => Err(BambuApiError::Unauthorized(e.to_string())),
+            Err(e) => {
+                // The current token still works for a while, try again next time
+                println!(
+                    "[BambuClient::ensure_valid_jwt] Failed to refresh jwt, keeping the current one: {}",
+                    e
+                );
+                Ok(())
+            }
+        }
+    }
+
     pub async fn login(
         &self,
         username: &str,
         password: &str,
-    ) -> Result<BambuUserResponse, BambuLoginError> {
+        cancel: Option<&CancelToken<'_>>,
+    ) -> Result<BambuLoginStep, BambuApiError> {
         let payload = json!(
             {
                 "account": username,
@@ -484,183 +1779,467 @@ impl BambuClient {
             }
         );
 
-        let response = self
-            .client
-            .post(constants::BAMBU_LOGIN_URL)
+        let request = self
+            .http()
+            .post(self.endpoints().login_url)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .body(payload.to_string())
-            .send()
-            .await;
+            .body(payload.to_string());
+        let response = self.send(request, cancel).await?;
+        let response = check_response(response).await.map_err(|e| {
+            e.rejected_credentials(
+                "Incorrect account or password, please check them and try again.",
+            )
+        })?;
+
+        let user_response = tokens_from_cookies(&response);
+        if !user_response.token.is_empty() {
+            self.complete_login(&user_response).await;
+            return Ok(BambuLoginStep::Complete(user_response));
+        }
 
-        match response {
-            Ok(response) => {
-                if !response.status().is_success() {
-                    return Err(BambuLoginError::IoError(
-                        (std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!(
-                                "Failed to login to Bambu with status code {}: \n\n{}",
-                                response.status(),
-                                response.text().await.unwrap_or("".to_string())
-                            ),
-                        ))
-                        .into(),
-                    ));
-                }
+        // No token means Bambu wants a second step, the body says which one
+        let response_text = response.text().await?;
+        let body: Value = parse_response(&response_text)?;
+        let login_type = body["loginType"].as_str().unwrap_or("").to_string();
 
-                // Get all set-cookies headers
-                let cookies = response
-                    .headers()
-                    .get_all(reqwest::header::SET_COOKIE)
-                    .iter()
-                    .filter_map(|value| value.to_str().ok())
-                    .flat_map(|value| value.split(';'))
-                    .map(|cookie| cookie.trim())
-                    .filter(|cookie| !cookie.is_empty());
-
-                let mut user_response = BambuUserResponse {
-                    token: String::new(),
-                    refresh_token: String::new(),
-                };
+        if login_type != "verifyCode" && login_type != "tfa" {
+            return Err(BambuApiError::Schema {
+                message: "Bambu did not return a token or a supported login type".to_string(),
+                body: response_text,
+            });
+        }
 
-                for cookie in cookies {
-                    if cookie.starts_with("token=") {
-                        user_response.token =
-                            cookie.split('=').collect::<Vec<&str>>()[1].to_string();
-                    } else if cookie.starts_with("refreshToken=") {
-                        user_response.refresh_token =
-                            cookie.split('=').collect::<Vec<&str>>()[1].to_string();
-                    }
-                }
+        println!(
+            "[BambuClient::login] Account: {} requires a {} code to login",
+            username, login_type
+        );
 
-                Ok(user_response)
-            }
-            Err(e) => Err(BambuLoginError::ReqwestError(e)),
-        }
+        *self.pending_login.lock().await = Some(PendingLogin {
+            account: username.to_string(),
+            login_type: login_type.clone(),
+            tfa_key: body["tfaKey"].as_str().unwrap_or("").to_string(),
+        });
+
+        Ok(BambuLoginStep::CodeRequired { login_type })
     }
 
-    pub async fn get_devices(&self) -> Result<BambuDeviceResponse, std::io::Error> {
-        // Ensure we have a token to use
-        let token =
-            match self.get_jwt().await {
-                Some(token) => token,
-                None => return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Expected a token to be set before calling get_devices, but none was found.",
-                )),
-            };
+    async fn complete_login(&self, user_response: &BambuUserResponse) {
+        *self.pending_login.lock().await = None;
+        *self.refresh_token.lock().await = Some(user_response.refresh_token.clone());
+    }
 
-        // Send a GET request with authorization header
-        let response = self
-            .client
-            .get(format!(
-                "{}/v1/iot-service/api/user/bind",
-                constants::BAMBU_API_URL
+    async fn get_pending_login(&self) -> Result<PendingLogin, BambuApiError> {
+        self.pending_login.lock().await.clone().ok_or_else(|| {
+            BambuApiError::Other(
+                "No login is waiting for a code. Please login with your username and password first."
+                    .to_string(),
+            )
+        })
+    }
+
+    // Ask Bambu to email a login code. Authenticator (tfa) codes don't need to be requested
+    pub async fn request_login_code(
+        &self,
+        cancel: Option<&CancelToken<'_>>,
+    ) -> Result<(), BambuApiError> {
+        let pending = self.get_pending_login().await?;
+
+        if pending.login_type != "verifyCode" {
+            return Err(BambuApiError::Other(
+                "This account uses an authenticator app, please enter the code it shows."
+                    .to_string(),
+            ));
+        }
+
+        let request = self
+            .http()
+            .post(format!(
+                "{}/v1/user-service/user/sendemail/code",
+                self.endpoints().api_url
             ))
-            .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
-            .send()
-            .await;
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json!({ "email": pending.account, "type": "codeLogin" }).to_string());
+        let response = self.send(request, cancel).await?;
+        check_response(response).await?;
 
-        match response {
-            Ok(response) => {
-                // Check if the response is successful
-                if !response.status().is_success() {
-                    // Return an error if the response is not successful
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!(
-                            "Failed to get devices from Bambu with status code {}: \n\n{}",
-                            response.status(),
-                            response.text().await.unwrap_or("".to_string())
-                        ),
-                    ));
-                }
+        println!(
+            "[BambuClient::request_login_code] Requested a login code for account: {}",
+            pending.account
+        );
 
-                let response_text = response.text().await.unwrap_or("".to_string());
+        Ok(())
+    }
 
-                println!(
-                    "[BambuClient::get_devices] response_text: {}",
-                    response_text
-                );
+    // Finish a login that needed an emailed or authenticator code
+    pub async fn submit_login_code(
+        &self,
+        code: &str,
+        cancel: Option<&CancelToken<'_>>,
+    ) -> Result<BambuUserResponse, BambuApiError> {
+        let pending = self.get_pending_login().await?;
+
+        let request = if pending.login_type == "tfa" {
+            self.http()
+                .post(self.endpoints().tfa_url)
+                .body(json!({ "tfaKey": pending.tfa_key, "tfaCode": code }).to_string())
+        } else {
+            self.http()
+                .post(format!(
+                    "{}/v1/user-service/user/login",
+                    self.endpoints().api_url
+                ))
+                .body(json!({ "account": pending.account, "code": code }).to_string())
+        };
 
-                // Parse the response body into a BambuDeviceResponse
-                let device_response: BambuDeviceResponse = serde_json::from_str(&response_text)
-                    .map_err(|e| {
-                        std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!(
-                                "Failed to parse Bambu device response: {}\n\n{}",
-                                e, response_text
-                            ),
-                        )
-                    })?;
-
-                // Return the parsed response
-                Ok(device_response)
+        let response = self
+            .send(
+                request.header(reqwest::header::CONTENT_TYPE, "application/json"),
+                cancel,
+            )
+            .await?;
+        let response = check_response(response).await.map_err(|e| {
+            e.rejected_credentials("The login code is incorrect or has expired, please try again.")
+        })?;
+
+        // The tfa endpoint sets cookies like the password form, the code login returns json
+        let mut user_response = tokens_from_cookies(&response);
+        if user_response.token.is_empty() {
+            let response_text = response.text().await?;
+            let body: Value = parse_response(&response_text)?;
+
+            user_response.token = body["accessToken"].as_str().unwrap_or("").to_string();
+            user_response.refresh_token = body["refreshToken"].as_str().unwrap_or("").to_string();
+
+            if user_response.token.is_empty() {
+                return Err(BambuApiError::Schema {
+                    message: "Bambu did not return a token for the login code".to_string(),
+                    body: response_text,
+                });
             }
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
         }
+
+        self.complete_login(&user_response).await;
+        Ok(user_response)
+    }
+
+    // Get a token for an authenticated request, refreshing it first if needed
+    async fn authorized_token(&self, caller: &str) -> Result<String, BambuApiError> {
+        self.ensure_valid_jwt().await?;
+
+        self.get_jwt().await.ok_or_else(|| {
+            BambuApiError::Other(format!(
+                "Expected a token to be set before calling {}, but none was found.",
+                caller
+            ))
+        })
     }
 
-    pub async fn get_device_ips(
+    // Send an authenticated request. The API answers 401 when a session is revoked or a
+    // token expires early, so refresh once and try again before giving up
+    async fn send_authorized<F>(
         &self,
-        devices: Vec<BambuDevice>,
-    ) -> Result<Vec<BambuDevice>, std::io::Error> {
+        caller: &str,
+        cancel: Option<&CancelToken<'_>>,
+        build: F,
+    ) -> Result<reqwest::Response, BambuApiError>
+    where
+        F: Fn(&reqwest::Client, &str) -> reqwest::RequestBuilder,
+    {
+        let token = self.authorized_token(caller).await?;
+        let response = self.send(build(&self.http(), &token), cancel).await?;
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
         println!(
-            "[BambuClient::get_device_ips] Starting discovery for {} devices using SSDP ...",
-            devices.len()
+            "[BambuClient::send_authorized] {} got 401, refreshing the jwt and trying again",
+            caller
         );
 
-        let ssdp_listeners = vec![SsdpListener::new(1990), SsdpListener::new(2021)];
-        let mut ssdp_messages: Vec<SsdpMessage> = vec![];
+        if self.get_jwt().await.as_deref() == Some(token.as_str()) {
+            self.refresh_jwt().await?;
+        }
 
-        for listener in ssdp_listeners {
-            println!("[BambuClient::get_device_ips] Running SSDP Discovery ...");
+        let token = self.authorized_token(caller).await?;
+        self.send(build(&self.http(), &token), cancel).await
+    }
 
-            // Wrap in a timeout to ensure we don't hang forever
-            let messages = tokio::time::timeout(
-                Duration::from_secs(5),
-                listener.listen(Duration::from_secs(5)),
-            );
+    pub async fn get_devices(
+        &self,
+        cancel: Option<&CancelToken<'_>>,
+    ) -> Result<BambuDeviceResponse, BambuApiError> {
+        // Send a GET request with authorization header
+        let response = self
+            .send_authorized("get_devices", cancel, |http, token| {
+                http.get(format!(
+                    "{}/v1/iot-service/api/user/bind",
+                    self.endpoints().api_url
+                ))
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            })
+            .await?;
+        let response = check_response(response).await?;
 
-            match messages.await {
-                Ok(Ok(messages)) => {
-                    println!(
-                        "[BambuClient::get_device_ips] Successfully discovered {} SSDP messages.",
-                        messages.len()
-                    );
-                    ssdp_messages.extend(messages);
-                }
-                Ok(Err(e)) => {
-                    println!(
-                        "[BambuClient::get_device_ips] Failed to discover SSDP messages: {}",
-                        e
-                    );
-                }
-                Err(_) => {
-                    println!(
-                        "[BambuClient::get_device_ips] Timed out while discovering SSDP messages."
-                    );
+        let response_text = response.text().await?;
 
-                    // Continue to the next listener
-                    continue;
-                }
+        println!(
+            "[BambuClient::get_devices] response_text: {}",
+            response_text
+        );
+
+        // Parse the response body into a BambuDeviceResponse
+        parse_response(&response_text)
+    }
+
+    // Bind a printer to the account with the pin code shown on its screen
+    pub async fn bind_device(
+        &self,
+        dev_id: &str,
+        pin_code: &str,
+        name: &str,
+    ) -> Result<(), BambuApiError> {
+        let response = self
+            .send_authorized("bind_device", None, |http, token| {
+                http.post(format!(
+                    "{}/v1/iot-service/api/user/bind",
+                    self.endpoints().api_url
+                ))
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json!({ "dev_id": dev_id, "pin_code": pin_code, "name": name }).to_string())
+            })
+            .await?;
+        check_action_response(response).await?;
+
+        println!("[BambuClient::bind_device] Bound device: {}", dev_id);
+        Ok(())
+    }
+
+    pub async fn unbind_device(&self, dev_id: &str) -> Result<(), BambuApiError> {
+        let response = self
+            .send_authorized("unbind_device", None, |http, token| {
+                http.delete(format!(
+                    "{}/v1/iot-service/api/user/bind",
+                    self.endpoints().api_url
+                ))
+                .query(&[("dev_id", dev_id)])
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            })
+            .await?;
+        check_action_response(response).await?;
+
+        println!("[BambuClient::unbind_device] Unbound device: {}", dev_id);
+        Ok(())
+    }
+
+    pub async fn rename_device(&self, dev_id: &str, name: &str) -> Result<(), BambuApiError> {
+        let response = self
+            .send_authorized("rename_device", None, |http, token| {
+                http.patch(format!(
+                    "{}/v1/iot-service/api/user/device/info",
+                    self.endpoints().api_url
+                ))
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json!({ "dev_id": dev_id, "name": name }).to_string())
+            })
+            .await?;
+        check_action_response(response).await?;
+
+        println!(
+            "[BambuClient::rename_device] Renamed device: {} to {}",
+            dev_id, name
+        );
+        Ok(())
+    }
+
+    // Combine the jwt claims with the account's preference endpoint, which is the only place
+    // the numeric uid is available
+    pub async fn get_account_profile(&self) -> Result<BambuAccountProfile, BambuApiError> {
+        let endpoints = self.endpoints();
+
+        let response = self
+            .send_authorized("get_account_profile", None, |http, token| {
+                http.get(format!(
+                    "{}/v1/design-user-service/my/preference",
+                    endpoints.api_url
+                ))
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            })
+            .await?;
+        let response = check_response(response).await?;
+
+        let mut profile = BambuAccountProfile {
+            region: endpoints.region.clone(),
+            ..Default::default()
+        };
+
+        // Newer accounts can get opaque tokens, so the claims are only a starting point
+        if let Some(Ok(claims)) = self.get_jwt().await.map(|token| decode_jwt(&token)) {
+            profile.username = if claims.preferred_username.is_empty() {
+                claims.username
+            } else {
+                claims.preferred_username
+            };
+        }
+
+        let response_text = response.text().await?;
+        let body: Value = parse_response(&response_text)?;
+
+        // The uid has been seen as both a number and a string
+        profile.uid = match &body["uid"] {
+            Value::Number(uid) => uid.as_i64().unwrap_or(0),
+            Value::String(uid) => uid.parse().unwrap_or(0),
+            _ => 0,
+        };
+
+        if profile.uid == 0 {
+            return Err(BambuApiError::Schema {
+                message: "Bambu did not return a user id".to_string(),
+                body: response_text,
+            });
+        }
+
+        profile.name = body["name"].as_str().unwrap_or("").to_string();
+        profile.avatar = body["avatar"].as_str().unwrap_or("").to_string();
+        if let Some(handle) = body["handle"]
+            .as_str()
+            .filter(|h| profile.username.is_empty() && !h.is_empty())
+        {
+            profile.username = handle.to_string();
+        }
+
+        println!(
+            "[BambuClient::get_account_profile] Got profile for uid: {}",
+            profile.uid
+        );
+
+        Ok(profile)
+    }
+
+    // One page of the account's print history, newest first
+    pub async fn fetch_task_history(
+        &self,
+        device_id: Option<&str>,
+        offset: u32,
+        limit: u32,
+    ) -> Result<BambuTaskPage, BambuApiError> {
+        let mut query = vec![("offset", offset.to_string()), ("limit", limit.to_string())];
+        if let Some(device_id) = device_id {
+            query.push(("deviceId", device_id.to_string()));
+        }
+
+        let response = self
+            .send_authorized("fetch_task_history", None, |http, token| {
+                http.get(format!(
+                    "{}/v1/user-service/my/tasks",
+                    self.endpoints().api_url
+                ))
+                .query(&query)
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            })
+            .await?;
+        let response = check_response(response).await?;
+
+        let response_text = response.text().await?;
+        parse_response(&response_text)
+    }
+
+    // The latest firmware the cloud offers for a device, None if it doesn't know the device
+    pub async fn get_latest_firmware(
+        &self,
+        dev_id: &str,
+    ) -> Result<Option<BambuDeviceFirmware>, BambuApiError> {
+        let response = self
+            .send_authorized("get_latest_firmware", None, |http, token| {
+                http.get(format!(
+                    "{}/v1/iot-service/api/user/device/version",
+                    self.endpoints().api_url
+                ))
+                .query(&[("dev_id", dev_id)])
+                .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+            })
+            .await?;
+        let response = check_response(response).await?;
+
+        let response_text = response.text().await?;
+        let versions: BambuFirmwareVersionResponse = parse_response(&response_text)?;
+
+        Ok(versions.devices.into_iter().find(|d| d.dev_id == dev_id))
+    }
+
+    // Listen for and search for Bambu printers on the LAN, one announcement per printer
+    pub async fn discover_announcements(&self) -> Result<Vec<BambuAnnouncement>, std::io::Error> {
+        println!("[BambuClient::discover_announcements] Starting discovery using SSDP ...");
+
+        let interfaces = load_discovery_settings().interface_addresses();
+
+        // Ask printers to answer while listening, some only announce every few minutes
+        let searcher = SsdpSearcher::new(vec![1900, 1990, 2021], interfaces.clone());
+        let search = tokio::spawn(async move {
+            searcher
+                .search(BAMBU_SEARCH_TARGET, Duration::from_secs(5))
+                .await
+        });
+
+        // Listen on both announcement ports for the whole window
+        let listener = SsdpListener::new(vec![1990, 2021], interfaces);
+        let mut ssdp_messages: Vec<SsdpMessage> = match listener
+            .listen(Duration::from_secs(5))
+            .await
+        {
+            Ok(messages) => {
+                println!(
+                    "[BambuClient::discover_announcements] Successfully discovered {} SSDP messages.",
+                    messages.len()
+                );
+                messages
+            }
+            Err(e) => {
+                println!(
+                    "[BambuClient::discover_announcements] Failed to discover SSDP messages: {}",
+                    e
+                );
+                vec![]
+            }
+        };
+
+        match search.await {
+            Ok(Ok(messages)) => {
+                println!(
+                    "[BambuClient::discover_announcements] M-SEARCH found {} SSDP messages.",
+                    messages.len()
+                );
+                ssdp_messages.extend(messages);
             }
+            Ok(Err(e)) => println!(
+                "[BambuClient::discover_announcements] M-SEARCH failed: {}",
+                e
+            ),
+            Err(e) => println!(
+                "[BambuClient::discover_announcements] M-SEARCH task failed: {}",
+                e
+            ),
         }
 
-        // de-dupe the messages by location
+        // de-dupe the messages by USN, the same printer answers on every port
         let mut unique_messages: Vec<SsdpMessage> = vec![];
         for message in ssdp_messages {
-            if !unique_messages
-                .iter()
-                .any(|m| m.location == message.location)
-            {
+            let is_duplicate = unique_messages.iter().any(|m| {
+                if message.usn.is_empty() {
+                    m.location == message.location
+                } else {
+                    m.usn == message.usn
+                }
+            });
+
+            if !is_duplicate {
                 unique_messages.push(message);
             }
         }
 
         if unique_messages.len() == 0 {
-            println!("[BambuClient::get_device_ips] No unique messages found. Exiting ...");
+            println!("[BambuClient::discover_announcements] No unique messages found. Exiting ...");
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "No unique messages found during SSDP discovery. Please ensure your devices are connected to the network and try again.",
@@ -668,21 +2247,68 @@ impl BambuClient {
         }
 
         println!(
-            "[BambuClient::get_device_ips] Finished SSDP discovery, found {} unique messages. Enriching...",
+            "[BambuClient::discover_announcements] Finished SSDP discovery, found {} unique messages.",
             unique_messages.len()
         );
 
-        let mut device_ips: Vec<BambuDevice> = vec![];
+        // Other UPnP devices answer too, only printers are interesting
+        Ok(unique_messages
+            .iter()
+            .filter_map(BambuAnnouncement::from_message)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        for mut device in devices {
-            let related_message = unique_messages.iter().find(|m| m.usn == device.dev_id);
+    fn encode_jwt(claims: Value) -> String {
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"not bambu's key"),
+        )
+        .unwrap()
+    }
 
-            if let Some(message) = related_message {
-                device.ip = Some(message.location.clone());
-                device_ips.push(device.clone());
-            }
-        }
+    #[test]
+    fn decodes_claims_without_checking_the_signature() {
+        let jwt = encode_jwt(json!({
+            "exp": 1710000000,
+            "iat": 1709990000,
+            "preferred_username": "maker",
+            "username": "u_1234",
+            "realm_access": { "roles": ["user"] }
+        }));
+
+        let claims = decode_jwt(&jwt).unwrap();
+        assert_eq!(claims.exp, 1710000000);
+        assert_eq!(claims.preferred_username, "maker");
+        assert_eq!(claims.username, "u_1234");
+        assert_eq!(claims.realm_access.roles, vec!["user"]);
+    }
+
+    #[test]
+    fn decodes_expired_tokens() {
+        let jwt = encode_jwt(json!({ "exp": 1 }));
+        assert_eq!(decode_jwt(&jwt).unwrap().exp, 1);
+        assert_eq!(jwt_expiry(&jwt), Some(1));
+    }
+
+    #[test]
+    fn missing_claims_fall_back_to_defaults() {
+        let jwt = encode_jwt(json!({ "sub": "someone" }));
+        let claims = decode_jwt(&jwt).unwrap();
+        assert_eq!(claims.exp, 0);
+        assert_eq!(claims.sub, "someone");
+        assert_eq!(jwt_expiry(&jwt), None);
+    }
 
-        Ok(device_ips)
+    #[test]
+    fn opaque_tokens_are_rejected() {
+        assert!(decode_jwt("not-a-jwt").is_err());
+        assert!(decode_jwt("a.b.c").is_err());
+        assert_eq!(jwt_expiry("opaque-session-token"), None);
     }
 }
@@ -0,0 +1,284 @@
+use super::bambu::BambuDevice;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_native_tls::TlsStream;
+
+// P1 and A1 series serve JPEG frames over TLS on this port, X1 series use RTSP instead
+const CAMERA_PORT: u16 = 6000;
+const CAMERA_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Anything bigger than this is a corrupt header rather than a frame
+const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct CameraFrame {
+    pub dev_id: String,
+    pub sequence: u64,
+    pub captured_at: i64, // unix timestamp in milliseconds
+    pub jpeg: Vec<u8>,
+}
+
+pub type CameraFrameReceiver = watch::Receiver<Option<Arc<CameraFrame>>>;
+
+fn camera_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+pub fn ensure_camera_supported(device: &BambuDevice) -> Result<(), io::Error> {
+    if device.dev_product_name.to_uppercase().starts_with("X1") {
+        return Err(camera_error(format!(
+            "Device: {} is an {}, which streams its camera over RTSP. Only P1 and A1 series cameras are supported.",
+            device.name, device.dev_product_name
+        )));
+    }
+
+    Ok(())
+}
+
+async fn connect(device: &BambuDevice) -> Result<TlsStream<TcpStream>, io::Error> {
+    let host = match &device.ip {
+        Some(ip) => ip.clone(),
+        None => {
+            return Err(camera_error(format!(
+                "Expected device: {} to have an IP address, but none was found.",
+                device.name
+            )));
+        }
+    };
+
+    let stream = tokio::time::timeout(
+        CAMERA_TIMEOUT,
+        TcpStream::connect(format!("{}:{}", host, CAMERA_PORT)),
+    )
+    .await
+    .map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Timed out connecting to camera at {}", host),
+        )
+    })??;
+
+    // The printers use a self-signed certificate, so there is nothing to verify against
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+        .map_err(|e| camera_error(format!("Failed to create TLS connector: {}", e)))?;
+
+    let mut stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(&host, stream)
+        .await
+        .map_err(|e| {
+            camera_error(format!(
+                "Failed to establish TLS with camera at {}: {}",
+                host, e
+            ))
+        })?;
+
+    // 80 byte auth packet: 0x40, 0x3000, two reserved words, then the username and access code padded to 32 bytes
+    let mut auth = Vec::with_capacity(80);
+    auth.extend_from_slice(&0x40u32.to_le_bytes());
+    auth.extend_from_slice(&0x3000u32.to_le_bytes());
+    auth.extend_from_slice(&0u32.to_le_bytes());
+    auth.extend_from_slice(&0u32.to_le_bytes());
+
+    for credential in ["bblp", device.dev_access_code.as_str()] {
+        let mut field = [0u8; 32];
+        let bytes = credential.as_bytes();
+        let len = bytes.len().min(32);
+        field[..len].copy_from_slice(&bytes[..len]);
+        auth.extend_from_slice(&field);
+    }
+
+    stream.write_all(&auth).await?;
+    stream.flush().await?;
+
+    println!(
+        "[camera::connect] Connected to camera for device: {}",
+        device.name
+    );
+
+    Ok(stream)
+}
+
+// Each frame is a 16 byte header (little endian payload size, then 12 bytes we don't need) followed by a JPEG
+async fn read_frame(stream: &mut TlsStream<TcpStream>) -> Result<Vec<u8>, io::Error> {
+    let mut header = [0u8; 16];
+    tokio::time::timeout(CAMERA_TIMEOUT, stream.read_exact(&mut header))
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for a camera frame",
+            )
+        })??;
+
+    let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if size == 0 || size > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid camera frame size: {}", size),
+        ));
+    }
+
+    let mut jpeg = vec![0u8; size];
+    tokio::time::timeout(CAMERA_TIMEOUT, stream.read_exact(&mut jpeg))
+        .await
+        .map_err(|_| {
+            io::Error::new(io::ErrorKind::TimedOut, "Timed out reading a camera frame")
+        })??;
+
+    if !jpeg.starts_with(&[0xFF, 0xD8]) || !jpeg.ends_with(&[0xFF, 0xD9]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Camera frame is not a complete JPEG",
+        ));
+    }
+
+    Ok(jpeg)
+}
+
+// Connect, grab one frame and disconnect
+pub async fn capture_single_frame(device: &BambuDevice) -> Result<Vec<u8>, io::Error> {
+    ensure_camera_supported(device)?;
+
+    let mut stream = connect(device).await?;
+    let jpeg = read_frame(&mut stream).await;
+    let _ = stream.shutdown().await;
+
+    jpeg
+}
+
+struct CameraStream {
+    receiver: CameraFrameReceiver,
+    handle: tokio::task::JoinHandle<()>,
+    viewers: usize,
+}
+
+// Keeps a single upstream connection per camera, shared between everyone watching it
+pub struct CameraManager {
+    streams: Mutex<HashMap<String, CameraStream>>,
+}
+
+impl CameraManager {
+    pub fn new() -> CameraManager {
+        CameraManager {
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Start watching a camera, connecting to it if nobody else is
+    pub fn subscribe(&self, device: &BambuDevice) -> Result<CameraFrameReceiver, io::Error> {
+        ensure_camera_supported(device)?;
+
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(stream) = streams.get_mut(&device.dev_id) {
+            stream.viewers += 1;
+            return Ok(stream.receiver.clone());
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        let stream_device = device.clone();
+
+        let handle = tokio::spawn(async move {
+            let device = stream_device;
+            let mut sequence: u64 = 0;
+
+            loop {
+                let result: Result<(), io::Error> = async {
+                    let mut stream = connect(&device).await?;
+
+                    loop {
+                        let jpeg = read_frame(&mut stream).await?;
+                        sequence += 1;
+
+                        let frame = CameraFrame {
+                            dev_id: device.dev_id.clone(),
+                            sequence,
+                            captured_at: chrono::Local::now().timestamp_millis(),
+                            jpeg,
+                        };
+
+                        let _ = sender.send(Some(Arc::new(frame)));
+                    }
+                }
+                .await;
+
+                if let Err(e) = result {
+                    println!(
+                        "[CameraManager::task::camera_stream] Camera stream for device: {} failed: {}. Reconnecting in {:?} ...",
+                        device.name, e, RECONNECT_DELAY
+                    );
+                }
+
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+
+        streams.insert(
+            device.dev_id.clone(),
+            CameraStream {
+                receiver: receiver.clone(),
+                handle,
+                viewers: 1,
+            },
+        );
+
+        println!(
+            "[CameraManager::subscribe] Started camera stream for device: {}",
+            device.name
+        );
+
+        Ok(receiver)
+    }
+
+    // Stop watching a camera, disconnecting from it once the last viewer is gone
+    pub fn release(&self, dev_id: &str) {
+        let mut streams = self.streams.lock().unwrap();
+
+        let remaining = match streams.get_mut(dev_id) {
+            Some(stream) => {
+                stream.viewers = stream.viewers.saturating_sub(1);
+                stream.viewers
+            }
+            None => return,
+        };
+
+        if remaining == 0 {
+            if let Some(stream) = streams.remove(dev_id) {
+                stream.handle.abort();
+            }
+
+            println!(
+                "[CameraManager::release] Stopped camera stream for device: {}",
+                dev_id
+            );
+        }
+    }
+
+    pub fn latest_frame(&self, dev_id: &str) -> Option<Arc<CameraFrame>> {
+        let streams = self.streams.lock().unwrap();
+        let stream = streams.get(dev_id)?;
+        let frame = stream.receiver.borrow().clone();
+        frame
+    }
+
+    // Use the live stream if there is a recent frame, otherwise connect just for this one
+    pub async fn snapshot(&self, device: &BambuDevice) -> Result<Vec<u8>, io::Error> {
+        const MAX_FRAME_AGE_MS: i64 = 2000;
+
+        if let Some(frame) = self.latest_frame(&device.dev_id) {
+            if chrono::Local::now().timestamp_millis() - frame.captured_at <= MAX_FRAME_AGE_MS {
+                return Ok(frame.jpeg.clone());
+            }
+        }
+
+        capture_single_frame(device).await
+    }
+}
@@ -1,13 +1,38 @@
-use super::bambu::BambuDevice;
+use super::bambu::{BambuAccountProfile, BambuCloudConfig, BambuDevice};
+use super::discovery::DiscoverySettings;
+use super::mjpeg::MjpegServerConfig;
+use super::network::NetworkSettings;
+use super::snapshots::SnapshotSettings;
+use super::sync::DeviceSyncSettings;
+use super::timelapse::TimelapseSettings;
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::fs::{self, File};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+lazy_static! {
+    // Held from load to save, so changes made at the same time don't overwrite each other
+    static ref CONFIG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn lock_config() -> MutexGuard<'static, ()> {
+    CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BambuInfo {
     pub jwt: String,
     pub refresh_token: String,
+    #[serde(default)]
+    pub jwt_expires_at: i64,
+    #[serde(default)]
+    pub refresh_token_expires_at: i64,
+    #[serde(default)]
+    pub jwt_last_refresh: i64,
+    #[serde(default)]
+    pub profile: Option<BambuAccountProfile>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,6 +40,22 @@ pub struct Config {
     pub is_first_run: bool,
     pub bambu_info: BambuInfo,
     pub bambu_devices: Vec<BambuDevice>,
+    #[serde(default)]
+    pub cloud: BambuCloudConfig,
+    #[serde(default)]
+    pub device_sync: DeviceSyncSettings,
+    #[serde(default)]
+    pub discovery: DiscoverySettings,
+    #[serde(default)]
+    pub media_download_dir: Option<String>,
+    #[serde(default)]
+    pub mjpeg_server: MjpegServerConfig,
+    #[serde(default)]
+    pub network: NetworkSettings,
+    #[serde(default)]
+    pub snapshot_settings: SnapshotSettings,
+    #[serde(default)]
+    pub timelapse_settings: TimelapseSettings,
 }
 
 impl Default for Config {
@@ -24,14 +65,31 @@ impl Default for Config {
             bambu_info: BambuInfo {
                 jwt: String::new(),
                 refresh_token: String::new(),
+                jwt_expires_at: 0,
+                refresh_token_expires_at: 0,
+                jwt_last_refresh: 0,
+                profile: None,
             },
             bambu_devices: Vec::new(),
+            cloud: BambuCloudConfig::default(),
+            device_sync: DeviceSyncSettings::default(),
+            discovery: DiscoverySettings::default(),
+            media_download_dir: None,
+            mjpeg_server: MjpegServerConfig::default(),
+            network: NetworkSettings::default(),
+            snapshot_settings: SnapshotSettings::default(),
+            timelapse_settings: TimelapseSettings::default(),
         }
     }
 }
 
 impl Config {
     pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        let _guard = lock_config();
+        Self::read_or_create(path)
+    }
+
+    fn read_or_create(path: &Path) -> io::Result<Self> {
         if path.exists() {
             serde_json::from_str(std::fs::read_to_string(path)?.as_str()).map_err(|e| {
                 io::Error::new(
@@ -40,11 +98,15 @@ impl Config {
                 )
             })
         } else {
-            Self::default().save(path)
+            Self::default().write(path)
         }
     }
 
-    pub fn save(self, path: &Path) -> io::Result<Self> {
+    pub fn find_device(&self, dev_id: &str) -> Option<&BambuDevice> {
+        self.bambu_devices.iter().find(|d| d.dev_id == dev_id)
+    }
+
+    fn write(self, path: &Path) -> io::Result<Self> {
         let mut file = File::create(path)?;
         let json = serde_json::to_string_pretty(&self)?;
         file.write_all(json.as_bytes())?;
@@ -52,7 +114,18 @@ impl Config {
     }
 }
 
-pub fn get_config_path() -> io::Result<PathBuf> {
+// Load, change and save the config in one go. Anything that changes part of the config
+// goes through here, so a sync, a discovery or a token refresh can't lose another's change
+pub fn update_config<T>(update: impl FnOnce(&mut Config) -> io::Result<T>) -> io::Result<T> {
+    let _guard = lock_config();
+    let path = get_config_path()?;
+    let mut config = Config::read_or_create(&path)?;
+    let result = update(&mut config)?;
+    config.write(&path)?;
+    Ok(result)
+}
+
+pub fn get_config_dir() -> io::Result<PathBuf> {
     let mut config_dir = dirs::config_dir().ok_or_else(|| {
         io::Error::new(
             io::ErrorKind::NotFound,
@@ -61,6 +134,33 @@ pub fn get_config_path() -> io::Result<PathBuf> {
     })?;
     config_dir.push("BambuConnect");
     fs::create_dir_all(&config_dir)?;
+    Ok(config_dir)
+}
+
+pub fn get_config_path() -> io::Result<PathBuf> {
+    let mut config_dir = get_config_dir()?;
     config_dir.push("config.json");
     Ok(config_dir)
 }
+
+// Where downloaded timelapses and recordings go unless the user picked a folder
+pub fn get_media_download_dir(config: &Config) -> io::Result<PathBuf> {
+    let dir = match &config.media_download_dir {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut dir = dirs::video_dir()
+                .or_else(dirs::download_dir)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Could not determine user's video or download directory",
+                    )
+                })?;
+            dir.push("BambuConnect");
+            dir
+        }
+    };
+
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
@@ -0,0 +1,250 @@
+use super::bambu::BambuDevice;
+use super::config::{get_config_path, update_config, Config};
+use super::network::{list_interfaces, NetworkInterface};
+use super::ssdp::{
+    BambuAnnouncement, SsdpListener, SsdpMessage, SsdpSearcher, BAMBU_SEARCH_TARGET,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DiscoverySettings {
+    pub continuous: bool,
+    pub search_interval_minutes: u64, // How often to M-SEARCH on top of listening, 0 disables
+    pub interfaces: Vec<String>,      // Names of the interfaces to discover on, empty for all
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        DiscoverySettings {
+            continuous: true,
+            search_interval_minutes: 5,
+            interfaces: vec![],
+        }
+    }
+}
+
+// A configured printer that showed up at a different address
+#[derive(Debug, Serialize, Clone)]
+pub struct DeviceIpChange {
+    pub device: BambuDevice, // With the new IP already set
+    pub old_ip: Option<String>,
+    pub new_ip: String,
+}
+
+// The latest announcement from every printer seen since discovery started
+pub type DiscoveredDevices = Arc<Mutex<HashMap<String, BambuAnnouncement>>>;
+
+pub fn load_discovery_settings() -> DiscoverySettings {
+    match get_config_path().and_then(|path| Config::load_or_create(&path)) {
+        Ok(config) => config.discovery,
+        Err(e) => {
+            println!(
+                "[discovery::load_discovery_settings] Failed to load config, using defaults: {}",
+                e
+            );
+            DiscoverySettings::default()
+        }
+    }
+}
+
+impl DiscoverySettings {
+    // Every IPv4 interface, marked with whether discovery uses it
+    pub fn list_interfaces(&self) -> io::Result<Vec<NetworkInterface>> {
+        let mut interfaces = list_interfaces()?;
+        for interface in interfaces.iter_mut() {
+            interface.selected = if self.interfaces.is_empty() {
+                !interface.is_loopback
+            } else {
+                self.interfaces.contains(&interface.name)
+            };
+        }
+
+        Ok(interfaces)
+    }
+
+    // The addresses to join multicast and search on. With no interfaces configured that's
+    // every non-loopback one, Docker bridges and VPNs included. Only an empty result, e.g. when
+    // the interfaces can't be listed, leaves it to the OS, which picks the default interface
+    pub fn interface_addresses(&self) -> Vec<Ipv4Addr> {
+        let interfaces = match self.list_interfaces() {
+            Ok(interfaces) => interfaces,
+            Err(e) => {
+                println!(
+                    "[discovery::interface_addresses] Failed to list network interfaces, using the default: {}",
+                    e
+                );
+                return vec![];
+            }
+        };
+
+        for name in self.interfaces.iter() {
+            if !interfaces.iter().any(|i| &i.name == name) {
+                println!(
+                    "[discovery::interface_addresses] Interface: {} is not available, skipping it",
+                    name
+                );
+            }
+        }
+
+        interfaces
+            .into_iter()
+            .filter(|i| i.selected)
+            .filter_map(|i| i.ip.parse().ok())
+            .collect()
+    }
+}
+
+// Store the new address of a configured printer. Returns None when the printer isn't
+// configured or is already at that address
+fn update_device_ip(announcement: &BambuAnnouncement) -> io::Result<Option<DeviceIpChange>> {
+    // Most announcements are for printers already at that address, don't rewrite the config for them
+    let config = Config::load_or_create(&get_config_path()?)?;
+    match config.find_device(&announcement.dev_id) {
+        Some(device) if device.ip.as_deref() != Some(announcement.ip.as_str()) => {}
+        _ => return Ok(None),
+    }
+
+    update_config(|config| {
+        let device = match config
+            .bambu_devices
+            .iter_mut()
+            .find(|d| d.dev_id == announcement.dev_id)
+        {
+            Some(device) => device,
+            None => return Ok(None),
+        };
+
+        if device.ip.as_deref() == Some(announcement.ip.as_str()) {
+            return Ok(None);
+        }
+
+        let old_ip = device.ip.replace(announcement.ip.clone());
+        Ok(Some(DeviceIpChange {
+            device: device.clone(),
+            old_ip,
+            new_ip: announcement.ip.clone(),
+        }))
+    })
+}
+
+async fn search_periodically(
+    searcher: SsdpSearcher,
+    interval: Duration,
+    messages: mpsc::UnboundedSender<SsdpMessage>,
+) {
+    loop {
+        match searcher
+            .search(BAMBU_SEARCH_TARGET, Duration::from_secs(5))
+            .await
+        {
+            Ok(found) => {
+                for message in found {
+                    if messages.send(message).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => println!("[discovery::search_periodically] M-SEARCH failed: {}", e),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Listens for printers for as long as it runs, keeping the configured IPs current. Address
+// changes are sent on ip_changes so the MQTT sessions can be moved over
+pub async fn run_discovery_service(
+    discovered: DiscoveredDevices,
+    ip_changes: mpsc::UnboundedSender<DeviceIpChange>,
+) {
+    let settings = load_discovery_settings();
+    if !settings.continuous {
+        println!("[discovery::run_discovery_service] Continuous discovery is disabled");
+        return;
+    }
+
+    let (sender, mut messages) = mpsc::unbounded_channel();
+    let interfaces = settings.interface_addresses();
+    let listener = SsdpListener::new(vec![1990, 2021], interfaces.clone());
+
+    // Searching still finds printers when the announcement ports are taken
+    let listen = async {
+        if let Err(e) = listener.listen_forever(sender.clone()).await {
+            println!(
+                "[discovery::run_discovery_service] Failed to listen for announcements: {}",
+                e
+            );
+        }
+        futures::future::pending::<()>().await
+    };
+
+    let search = async {
+        if settings.search_interval_minutes == 0 {
+            return futures::future::pending::<()>().await;
+        }
+
+        search_periodically(
+            SsdpSearcher::new(vec![1900, 1990, 2021], interfaces.clone()),
+            Duration::from_secs(settings.search_interval_minutes * 60),
+            sender.clone(),
+        )
+        .await
+    };
+
+    let process = async {
+        while let Some(message) = messages.recv().await {
+            let announcement = match BambuAnnouncement::from_message(&message) {
+                Some(announcement) => announcement,
+                None => continue,
+            };
+
+            // Printers announce every few seconds, only look at the config when something moved
+            let previous = discovered
+                .lock()
+                .await
+                .insert(announcement.dev_id.clone(), announcement.clone());
+            if previous.as_ref().map(|p| &p.ip) == Some(&announcement.ip) {
+                continue;
+            }
+
+            match update_device_ip(&announcement) {
+                Ok(Some(change)) => {
+                    println!(
+                        "[discovery::run_discovery_service] Device: {} moved from {:?} to {}",
+                        change.device.name, change.old_ip, change.new_ip
+                    );
+                    let _ = ip_changes.send(change);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    println!(
+                        "[discovery::run_discovery_service] Failed to update the IP of device: {}: {}",
+                        announcement.dev_id, e
+                    );
+
+                    // Forget the new IP so the next announcement tries the update again
+                    let mut discovered = discovered.lock().await;
+                    match previous {
+                        Some(previous) => discovered.insert(previous.dev_id.clone(), previous),
+                        None => discovered.remove(&announcement.dev_id),
+                    };
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = listen => {}
+        _ = search => {}
+        _ = process => {}
+    }
+
+    println!("[discovery::run_discovery_service] Stopped discovery");
+}
@@ -0,0 +1,554 @@
+use super::bambu::{BambuAmsFirmware, BambuDevice, BambuDeviceFirmware, BambuFirmwareRelease};
+use serde::Serialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+// A module as reported by info.get_version, e.g. { "name": "ams/0", "sw_ver": "00.00.06.40", ... }
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct ModuleVersion {
+    pub name: String,
+    #[serde(default)]
+    pub kind: String, // ota, ams, mc, toolhead, camera or other
+    #[serde(default)]
+    pub sw_ver: String,
+    #[serde(default)]
+    pub hw_ver: String,
+    #[serde(default)]
+    pub sn: String,
+}
+
+// An AMS unit and the newest firmware the cloud offers for its hardware
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct AmsFirmwareEntry {
+    pub name: String,     // e.g. ams/0 or n3f/1
+    pub hardware: String, // ams, n3s (AMS lite) or n3f (AMS 2)
+    pub current_version: String,
+    pub latest_version: Option<String>,
+    pub update_available: bool,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct FirmwareReportEntry {
+    pub dev_id: String,
+    pub name: String,
+    pub model: String,
+    pub modules: Vec<ModuleVersion>,
+    pub current_version: Option<String>,
+    pub latest_version: Option<String>,
+    pub update_available: bool,
+    pub force_update: bool,
+    pub ams: Vec<AmsFirmwareEntry>,
+    pub ams_update_available: bool,
+    pub error: Option<String>,
+}
+
+// Module names differ a little between models, the AMS lite and AMS 2 report as n3s / n3f
+fn module_kind(name: &str) -> &'static str {
+    match name {
+        "ota" => "ota",
+        "mc" => "mc",
+        "th" => "toolhead",
+        "xm" | "camera" => "camera",
+        _ if name.starts_with("ams") || name.starts_with("n3") => "ams",
+        _ => "other",
+    }
+}
+
+// Read the modules out of an info.get_version response
+pub fn parse_module_versions(report: &Value) -> Option<Vec<ModuleVersion>> {
+    let info = report.get("info")?;
+    if info.get("command").and_then(|c| c.as_str()) != Some("get_version") {
+        return None;
+    }
+
+    let mut modules: Vec<ModuleVersion> =
+        serde_json::from_value(info.get("module")?.clone()).ok()?;
+    for module in modules.iter_mut() {
+        module.kind = module_kind(&module.name).to_string();
+    }
+
+    Some(modules)
+}
+
+// Versions are dotted numbers like 01.07.00.00, compare them part by part
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parts = |v: &str| -> Vec<u32> {
+        v.split('.')
+            .map(|p| p.trim().parse::<u32>().unwrap_or(0))
+            .collect()
+    };
+
+    let (a, b) = (parts(a), parts(b));
+    for i in 0..a.len().max(b.len()) {
+        let ordering = a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
+
+fn newest_release(releases: &[BambuFirmwareRelease]) -> Option<&BambuFirmwareRelease> {
+    releases
+        .iter()
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+}
+
+// The hardware an AMS module belongs to and its unit index, e.g. n3f/1 -> ("n3f", Some(1))
+fn ams_unit(name: &str) -> (&'static str, Option<u32>) {
+    let (hardware, index) = name.split_once('/').unwrap_or((name, ""));
+    let hardware = if hardware.starts_with("n3s") {
+        "n3s"
+    } else if hardware.starts_with("n3f") {
+        "n3f"
+    } else {
+        "ams"
+    };
+
+    (hardware, index.parse().ok())
+}
+
+// Same naming for the cloud's model names, None when it didn't send one
+fn ams_model_hardware(model: &str) -> Option<&'static str> {
+    let model = model.to_lowercase();
+    if model.is_empty() {
+        None
+    } else if model.contains("n3s") {
+        Some("n3s")
+    } else if model.contains("n3f") {
+        Some("n3f")
+    } else {
+        Some("ams")
+    }
+}
+
+// The cloud entry for an AMS unit: the one at its address, or without addresses the only
+// entry that can be for its hardware. Releases for one kind of AMS don't apply to another
+fn find_ams_firmware<'a>(
+    module: &ModuleVersion,
+    ams: &'a [BambuAmsFirmware],
+) -> Option<&'a BambuAmsFirmware> {
+    let (hardware, index) = ams_unit(&module.name);
+    let candidates: Vec<&BambuAmsFirmware> = ams
+        .iter()
+        .filter(|a| !matches!(ams_model_hardware(&a.dev_model_name), Some(h) if h != hardware))
+        .collect();
+
+    if let Some(found) = candidates
+        .iter()
+        .find(|a| a.address.is_some() && a.address == index)
+    {
+        return Some(found);
+    }
+
+    let unaddressed: Vec<&&BambuAmsFirmware> =
+        candidates.iter().filter(|a| a.address.is_none()).collect();
+    match unaddressed.as_slice() {
+        [only] => Some(only),
+        _ => None,
+    }
+}
+
+fn is_behind(current: Option<&str>, latest: Option<&str>) -> bool {
+    match (current, latest) {
+        (Some(current), Some(latest)) => compare_versions(current, latest) == Ordering::Less,
+        _ => false,
+    }
+}
+
+// Put what the printer reports next to what the cloud offers for it
+pub fn build_report_entry(
+    device: &BambuDevice,
+    modules: Result<Vec<ModuleVersion>, String>,
+    latest: Result<Option<BambuDeviceFirmware>, String>,
+) -> FirmwareReportEntry {
+    let mut entry = FirmwareReportEntry {
+        dev_id: device.dev_id.clone(),
+        name: device.name.clone(),
+        model: device.dev_product_name.clone(),
+        modules: vec![],
+        current_version: None,
+        latest_version: None,
+        update_available: false,
+        force_update: false,
+        ams: vec![],
+        ams_update_available: false,
+        error: None,
+    };
+
+    let mut errors = vec![];
+
+    match modules {
+        Ok(modules) => {
+            entry.current_version = modules
+                .iter()
+                .find(|m| m.kind == "ota")
+                .map(|m| m.sw_ver.clone());
+            entry.ams = modules
+                .iter()
+                .filter(|m| m.kind == "ams")
+                .map(|m| AmsFirmwareEntry {
+                    name: m.name.clone(),
+                    hardware: ams_unit(&m.name).0.to_string(),
+                    current_version: m.sw_ver.clone(),
+                    latest_version: None,
+                    update_available: false,
+                })
+                .collect();
+            entry.modules = modules;
+        }
+        Err(e) => errors.push(e),
+    }
+
+    match latest {
+        Ok(Some(latest)) => {
+            if let Some(release) = newest_release(&latest.firmware) {
+                entry.latest_version = Some(release.version.clone());
+                entry.force_update = release.force_update;
+            }
+
+            for ams in entry.ams.iter_mut() {
+                let module = entry.modules.iter().find(|m| m.name == ams.name);
+                ams.latest_version = module
+                    .and_then(|m| find_ams_firmware(m, &latest.ams))
+                    .and_then(|a| newest_release(&a.firmware))
+                    .map(|r| r.version.clone());
+            }
+        }
+        Ok(None) => {
+            errors.push("The cloud has no firmware information for this device".to_string())
+        }
+        Err(e) => errors.push(e),
+    }
+
+    entry.update_available = is_behind(
+        entry.current_version.as_deref(),
+        entry.latest_version.as_deref(),
+    );
+    for ams in entry.ams.iter_mut() {
+        ams.update_available = is_behind(
+            Some(ams.current_version.as_str()),
+            ams.latest_version.as_deref(),
+        );
+    }
+    entry.ams_update_available = entry.ams.iter().any(|a| a.update_available);
+
+    if !errors.is_empty() {
+        entry.error = Some(errors.join("; "));
+    }
+
+    entry
+}
+
+// print.upgrade_state, which printers include in every full report
+#[derive(Debug, Serialize, Clone, PartialEq, Default)]
+pub struct UpgradeState {
+    pub status: String, // IDLE, UPGRADE_REQUEST, DOWNLOADING, FLASHING, UPGRADE_SUCCESS, UPGRADE_FAIL, ...
+    pub progress: u32,
+    pub module: Option<String>,
+    pub new_version: Option<String>,
+    pub error_code: i64,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UpgradeProgressEvent {
+    pub dev_id: String,
+    pub name: String,
+    pub state: UpgradeState,
+}
+
+// Numbers in upgrade_state are sometimes sent as strings
+fn number_field(value: &Value) -> Option<i64> {
+    value
+        .as_i64()
+        .or_else(|| value.as_str().and_then(|v| v.trim().parse().ok()))
+}
+
+// Strings are "null" or empty when there's nothing to report
+fn string_field(value: &Value) -> Option<String> {
+    value
+        .as_str()
+        .filter(|v| !v.is_empty() && *v != "null")
+        .map(|v| v.to_string())
+}
+
+impl UpgradeState {
+    pub fn from_report(report: &Value) -> Option<UpgradeState> {
+        let upgrade = report.get("print")?.get("upgrade_state")?;
+        let status = upgrade.get("status").and_then(|s| s.as_str())?.to_string();
+
+        let module = string_field(&upgrade["module"]);
+
+        // new_ver_list has an entry per module, fall back to the main firmware version
+        let new_version = upgrade["new_ver_list"]
+            .as_array()
+            .and_then(|list| {
+                list.iter()
+                    .find(|v| module.is_some() && string_field(&v["name"]) == module)
+                    .and_then(|v| string_field(&v["new_ver"]))
+            })
+            .or_else(|| string_field(&upgrade["ota_new_version_number"]));
+
+        Some(UpgradeState {
+            status,
+            progress: number_field(&upgrade["progress"])
+                .unwrap_or(0)
+                .clamp(0, 100) as u32,
+            module,
+            new_version,
+            error_code: number_field(&upgrade["err_code"]).unwrap_or(0),
+            message: string_field(&upgrade["message"]),
+        })
+    }
+
+    pub fn in_progress(&self) -> bool {
+        !(self.status.is_empty()
+            || self.status == "IDLE"
+            || self.status == "UPGRADE_SUCCESS"
+            || self.status.ends_with("_FAIL"))
+    }
+}
+
+// Starting a job or moving anything while flashing can brick a module. Status requests,
+// info queries and stopping are still fine
+pub fn blocked_during_upgrade(payload: &Value) -> bool {
+    match payload
+        .get("print")
+        .and_then(|p| p.get("command"))
+        .and_then(|c| c.as_str())
+    {
+        Some(command) => !matches!(command, "stop" | "pause"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn module(name: &str, sw_ver: &str) -> ModuleVersion {
+        ModuleVersion {
+            name: name.to_string(),
+            kind: module_kind(name).to_string(),
+            sw_ver: sw_ver.to_string(),
+            hw_ver: String::new(),
+            sn: String::new(),
+        }
+    }
+
+    fn release(version: &str) -> BambuFirmwareRelease {
+        BambuFirmwareRelease {
+            version: version.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ams(address: Option<u32>, model: &str, versions: &[&str]) -> BambuAmsFirmware {
+        BambuAmsFirmware {
+            address,
+            dev_model_name: model.to_string(),
+            firmware: versions.iter().map(|v| release(v)).collect(),
+        }
+    }
+
+    fn device() -> BambuDevice {
+        serde_json::from_value(json!({
+            "dev_id": "01P00A000000001",
+            "name": "P1S",
+            "online": true,
+            "print_status": "IDLE",
+            "dev_model_name": "C12",
+            "dev_product_name": "P1S",
+            "dev_access_code": "12345678",
+            "nozzle_diameter": 0.4
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn compares_versions_part_by_part() {
+        assert_eq!(
+            compare_versions("01.07.00.00", "01.07.00.00"),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare_versions("01.06.10.00", "01.07.00.00"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_versions("01.10.00.00", "01.09.99.99"),
+            Ordering::Greater
+        );
+        assert_eq!(compare_versions("1.7.0.0", "01.07.00.00"), Ordering::Equal);
+    }
+
+    #[test]
+    fn missing_and_garbled_parts_count_as_zero() {
+        assert_eq!(compare_versions("01.07", "01.07.00.00"), Ordering::Equal);
+        assert_eq!(compare_versions("01.07.00.01", "01.07"), Ordering::Greater);
+        assert_eq!(
+            compare_versions("01.x.00.00", "01.00.00.00"),
+            Ordering::Equal
+        );
+        assert_eq!(compare_versions("", "00.00.00.01"), Ordering::Less);
+    }
+
+    #[test]
+    fn matches_ams_releases_by_address_and_hardware() {
+        let modules = vec![
+            module("ota", "01.06.00.00"),
+            module("ams/0", "00.00.06.40"),
+            module("n3f/1", "00.00.01.00"),
+        ];
+        let latest = BambuDeviceFirmware {
+            dev_id: "01P00A000000001".to_string(),
+            firmware: vec![release("01.05.00.00"), release("01.07.00.00")],
+            ams: vec![
+                ams(Some(0), "BL-A001", &["00.00.06.40"]),
+                ams(Some(1), "N3F", &["00.00.01.00", "00.00.02.00"]),
+            ],
+        };
+
+        let entry = build_report_entry(&device(), Ok(modules), Ok(Some(latest)));
+
+        assert_eq!(entry.latest_version.as_deref(), Some("01.07.00.00"));
+        assert!(entry.update_available);
+
+        assert_eq!(entry.ams.len(), 2);
+        assert_eq!(entry.ams[0].hardware, "ams");
+        assert_eq!(entry.ams[0].latest_version.as_deref(), Some("00.00.06.40"));
+        assert!(!entry.ams[0].update_available);
+        assert_eq!(entry.ams[1].hardware, "n3f");
+        assert_eq!(entry.ams[1].latest_version.as_deref(), Some("00.00.02.00"));
+        assert!(entry.ams[1].update_available);
+        assert!(entry.ams_update_available);
+        assert!(entry.error.is_none());
+    }
+
+    #[test]
+    fn other_ams_hardware_releases_dont_apply() {
+        let modules = vec![module("n3s/0", "00.01.00.00")];
+        let latest = BambuDeviceFirmware {
+            ams: vec![ams(Some(0), "BL-A001", &["00.00.09.00"])],
+            ..Default::default()
+        };
+
+        let entry = build_report_entry(&device(), Ok(modules), Ok(Some(latest)));
+
+        assert_eq!(entry.ams[0].hardware, "n3s");
+        assert_eq!(entry.ams[0].latest_version, None);
+        assert!(!entry.ams_update_available);
+    }
+
+    #[test]
+    fn unaddressed_release_only_matches_when_unambiguous() {
+        let modules = vec![
+            module("ams/0", "00.00.06.00"),
+            module("ams/1", "00.00.06.00"),
+        ];
+
+        let single = BambuDeviceFirmware {
+            ams: vec![ams(None, "", &["00.00.06.40"])],
+            ..Default::default()
+        };
+        let entry = build_report_entry(&device(), Ok(modules.clone()), Ok(Some(single)));
+        assert!(entry.ams.iter().all(|a| a.update_available));
+
+        let several = BambuDeviceFirmware {
+            ams: vec![
+                ams(None, "", &["00.00.06.40"]),
+                ams(None, "", &["00.00.07.00"]),
+            ],
+            ..Default::default()
+        };
+        let entry = build_report_entry(&device(), Ok(modules), Ok(Some(several)));
+        assert!(entry.ams.iter().all(|a| a.latest_version.is_none()));
+    }
+
+    #[test]
+    fn reports_errors_from_both_sides() {
+        let entry = build_report_entry(
+            &device(),
+            Err("Device did not answer".to_string()),
+            Ok(None),
+        );
+
+        assert!(entry.modules.is_empty());
+        assert!(!entry.update_available);
+        assert_eq!(
+            entry.error.as_deref(),
+            Some("Device did not answer; The cloud has no firmware information for this device")
+        );
+    }
+
+    #[test]
+    fn reads_upgrade_state_for_the_module_being_flashed() {
+        let report = json!({
+            "print": {
+                "upgrade_state": {
+                    "status": "FLASHING",
+                    "progress": "42",
+                    "module": "ams/0",
+                    "new_ver_list": [
+                        { "name": "ota", "new_ver": "01.07.00.00" },
+                        { "name": "ams/0", "new_ver": "00.00.06.49" }
+                    ],
+                    "ota_new_version_number": "01.07.00.00",
+                    "err_code": 0,
+                    "message": ""
+                }
+            }
+        });
+
+        let state = UpgradeState::from_report(&report).unwrap();
+        assert_eq!(state.status, "FLASHING");
+        assert_eq!(state.progress, 42);
+        assert_eq!(state.module.as_deref(), Some("ams/0"));
+        assert_eq!(state.new_version.as_deref(), Some("00.00.06.49"));
+        assert_eq!(state.message, None);
+        assert!(state.in_progress());
+    }
+
+    #[test]
+    fn falls_back_to_the_main_firmware_version() {
+        let report = json!({
+            "print": {
+                "upgrade_state": {
+                    "status": "UPGRADE_FAIL",
+                    "progress": 250,
+                    "module": "null",
+                    "new_ver_list": [],
+                    "ota_new_version_number": "01.07.00.00",
+                    "err_code": "12",
+                    "message": "Verification failed"
+                }
+            }
+        });
+
+        let state = UpgradeState::from_report(&report).unwrap();
+        assert_eq!(state.progress, 100);
+        assert_eq!(state.module, None);
+        assert_eq!(state.new_version.as_deref(), Some("01.07.00.00"));
+        assert_eq!(state.error_code, 12);
+        assert_eq!(state.message.as_deref(), Some("Verification failed"));
+        assert!(!state.in_progress());
+    }
+
+    #[test]
+    fn reports_without_an_upgrade_status_are_ignored() {
+        assert!(UpgradeState::from_report(&json!({ "print": { "mc_percent": 10 } })).is_none());
+        assert!(UpgradeState::from_report(&json!({ "print": { "upgrade_state": {} } })).is_none());
+        assert!(UpgradeState::from_report(&json!({ "info": {} })).is_none());
+
+        let idle = UpgradeState::from_report(&json!({
+            "print": { "upgrade_state": { "status": "IDLE" } }
+        }))
+        .unwrap();
+        assert_eq!(idle.progress, 0);
+        assert_eq!(idle.new_version, None);
+        assert!(!idle.in_progress());
+    }
+}
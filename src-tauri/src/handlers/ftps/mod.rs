@@ -0,0 +1,462 @@
+use super::bambu::BambuDevice;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_native_tls::TlsStream;
+
+// Bambu printers serve their SD card over implicit FTPS
+const FTPS_PORT: u16 = 990;
+const FTPS_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct BambuFtpsClient {
+    host: String,
+    connector: tokio_native_tls::TlsConnector,
+    control: BufReader<TlsStream<TcpStream>>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct FtpsEntry {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<i64>, // unix timestamp, in the printer's clock
+}
+
+impl FtpsEntry {
+    // Parse a unix style LIST line:
+    // -rw-rw-rw-   1 root  root  12345678 Mar 05 10:21 video_2024-03-05_10-21-33.avi
+    fn from_list_line(directory: &str, line: &str) -> Option<FtpsEntry> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 9 || !parts[0].starts_with('-') {
+            return None;
+        }
+
+        let size = parts[4].parse::<u64>().ok()?;
+        let name = parts[8..].join(" ");
+        let modified = parse_list_time(parts[5], parts[6], parts[7], chrono::Local::now());
+
+        Some(FtpsEntry {
+            path: format!("{}/{}", directory, name),
+            name,
+            size,
+            modified,
+        })
+    }
+}
+
+// LIST shows "Mon DD HH:MM" for files from the last year and "Mon DD YYYY" for older ones
+fn parse_list_time(
+    month: &str,
+    day: &str,
+    time_or_year: &str,
+    now: chrono::DateTime<chrono::Local>,
+) -> Option<i64> {
+    use chrono::{Datelike, NaiveDate, NaiveTime, TimeZone};
+
+    let month = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ]
+    .iter()
+    .position(|m| month.to_lowercase().starts_with(m))? as u32
+        + 1;
+    let day = day.parse::<u32>().ok()?;
+
+    let to_timestamp = |year: i32, time: NaiveTime| {
+        let date = NaiveDate::from_ymd_opt(year, month, day)?;
+        chrono::Local
+            .from_local_datetime(&date.and_time(time))
+            .earliest()
+            .map(|t| t.timestamp())
+    };
+
+    match time_or_year.split_once(':') {
+        Some((hour, minute)) => {
+            let time = NaiveTime::from_hms_opt(hour.parse().ok()?, minute.parse().ok()?, 0)?;
+
+            // No year means within the last year, so a date ahead of us is from last year. A day
+            // of slack keeps a printer clock that runs a little ahead from moving files back a year
+            match to_timestamp(now.year(), time) {
+                Some(timestamp) if timestamp > now.timestamp() + 24 * 60 * 60 => {
+                    to_timestamp(now.year() - 1, time)
+                }
+                timestamp => timestamp,
+            }
+        }
+        None => to_timestamp(
+            time_or_year.parse::<i32>().ok()?,
+            NaiveTime::from_hms_opt(0, 0, 0)?,
+        ),
+    }
+}
+
+fn ftps_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+impl BambuFtpsClient {
+    pub async fn connect(device: &BambuDevice) -> Result<BambuFtpsClient, io::Error> {
+        let host = match &device.ip {
+            Some(ip) => ip.clone(),
+            None => {
+                return Err(ftps_error(format!(
+                    "Expected device: {} to have an IP address, but none was found.",
+                    device.name
+                )));
+            }
+        };
+
+        // The printers use a self-signed certificate, so there is nothing to verify against
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .map_err(|e| ftps_error(format!("Failed to create TLS connector: {}", e)))?;
+        let connector = tokio_native_tls::TlsConnector::from(connector);
+
+        let stream = tokio::time::timeout(
+            FTPS_TIMEOUT,
+            TcpStream::connect(format!("{}:{}", host, FTPS_PORT)),
+        )
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Timed out connecting to FTPS server at {}", host),
+            )
+        })??;
+
+        let stream = connector.connect(&host, stream).await.map_err(|e| {
+            ftps_error(format!(
+                "Failed to establish TLS with FTPS server at {}: {}",
+                host, e
+            ))
+        })?;
+
+        let mut client = BambuFtpsClient {
+            host,
+            connector,
+            control: BufReader::new(stream),
+        };
+
+        client.expect_response(&[220]).await?;
+        client.command("USER bblp", &[331]).await?;
+        client
+            .command(&format!("PASS {}", device.dev_access_code), &[230])
+            .await?;
+
+        // Protect the data channel as well and switch to binary transfers
+        client.command("PBSZ 0", &[200]).await?;
+        client.command("PROT P", &[200]).await?;
+        client.command("TYPE I", &[200]).await?;
+
+        println!(
+            "[BambuFtpsClient::connect] Connected to FTPS server for device: {}",
+            device.name
+        );
+
+        Ok(client)
+    }
+
+    async fn read_response(&mut self) -> Result<(u16, String), io::Error> {
+        let mut line = String::new();
+        let mut message = String::new();
+
+        loop {
+            line.clear();
+            let read = tokio::time::timeout(FTPS_TIMEOUT, self.control.read_line(&mut line))
+                .await
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Timed out waiting for FTPS server at {}", self.host),
+                    )
+                })??;
+
+            if read == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("FTPS server at {} closed the connection", self.host),
+                ));
+            }
+
+            message.push_str(&line);
+
+            // Multi-line replies start with "123-" and end with "123 "
+            let code = line.get(0..3).and_then(|code| code.parse::<u16>().ok());
+            if let Some(code) = code {
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    return Ok((code, message.trim_end().to_string()));
+                }
+            }
+        }
+    }
+
+    async fn expect_response(&mut self, codes: &[u16]) -> Result<String, io::Error> {
+        let (code, message) = self.read_response().await?;
+
+        if !codes.contains(&code) {
+            return Err(ftps_error(format!(
+                "Unexpected response from FTPS server at {}: {}",
+                self.host, message
+            )));
+        }
+
+        Ok(message)
+    }
+
+    async fn command(&mut self, command: &str, codes: &[u16]) -> Result<String, io::Error> {
+        self.control
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+
+        self.expect_response(codes).await
+    }
+
+    // Enter passive mode and open the (not yet encrypted) data connection
+    async fn open_data_connection(&mut self) -> Result<TcpStream, io::Error> {
+        let response = self.command("PASV", &[227]).await?;
+
+        // 227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)
+        let numbers: Vec<u16> = response
+            .split(['(', ')'])
+            .nth(1)
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|part| part.trim().parse::<u16>().ok())
+            .collect();
+
+        if numbers.len() != 6 {
+            return Err(ftps_error(format!(
+                "Failed to parse passive mode response: {}",
+                response
+            )));
+        }
+
+        // Always use the control connection's host, the printer may advertise an internal address
+        let port = numbers[4] * 256 + numbers[5];
+        let stream = tokio::time::timeout(
+            FTPS_TIMEOUT,
+            TcpStream::connect(format!("{}:{}", self.host, port)),
+        )
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Timed out opening FTPS data connection to {}", self.host),
+            )
+        })??;
+
+        Ok(stream)
+    }
+
+    async fn secure_data_connection(
+        &self,
+        stream: TcpStream,
+    ) -> Result<TlsStream<TcpStream>, io::Error> {
+        self.connector
+            .connect(&self.host, stream)
+            .await
+            .map_err(|e| ftps_error(format!("Failed to secure FTPS data connection: {}", e)))
+    }
+
+    // Send a command that transfers over a data connection, and return the secured data stream
+    async fn start_transfer(&mut self, command: &str) -> Result<TlsStream<TcpStream>, io::Error> {
+        let stream = self.open_data_connection().await?;
+        self.command(command, &[125, 150]).await?;
+        self.secure_data_connection(stream).await
+    }
+
+    async fn finish_transfer(&mut self, mut stream: TlsStream<TcpStream>) -> Result<(), io::Error> {
+        let _ = stream.shutdown().await;
+        drop(stream);
+
+        self.expect_response(&[226, 250]).await?;
+        Ok(())
+    }
+
+    // Download a whole file into memory
+    pub async fn retrieve(&mut self, path: &str) -> Result<Vec<u8>, io::Error> {
+        let mut data_stream = self.start_transfer(&format!("RETR {}", path)).await?;
+        let mut data = vec![];
+        data_stream.read_to_end(&mut data).await?;
+        self.finish_transfer(data_stream).await?;
+
+        println!(
+            "[BambuFtpsClient::retrieve] Downloaded {} ({} bytes) from {}",
+            path,
+            data.len(),
+            self.host
+        );
+
+        Ok(data)
+    }
+
+    // Download a file to disk, reporting the number of bytes written so far.
+    // The file is written next to the destination first, so a failed or cancelled download never leaves a partial file behind.
+    // Cancelling leaves the control connection mid-transfer, so the client shouldn't be used afterwards.
+    pub async fn retrieve_to_file<F>(
+        &mut self,
+        path: &str,
+        destination: &Path,
+        cancel: &mut watch::Receiver<bool>,
+        mut on_progress: F,
+    ) -> Result<u64, io::Error>
+    where
+        F: FnMut(u64),
+    {
+        let mut partial_path = destination.as_os_str().to_owned();
+        partial_path.push(".part");
+
+        let mut file = tokio::fs::File::create(&partial_path).await?;
+
+        let result: Result<u64, io::Error> = async {
+            let mut data_stream = self.start_transfer(&format!("RETR {}", path)).await?;
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut written: u64 = 0;
+
+            loop {
+                let read = tokio::select! {
+                    read = tokio::time::timeout(FTPS_TIMEOUT, data_stream.read(&mut buf)) => {
+                        read.map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                format!("Timed out downloading {} from {}", path, self.host),
+                            )
+                        })??
+                    }
+                    _ = cancel.wait_for(|cancelled| *cancelled) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Interrupted,
+                            format!("Download of {} was cancelled", path),
+                        ));
+                    }
+                };
+
+                if read == 0 {
+                    break;
+                }
+
+                file.write_all(&buf[..read]).await?;
+                written += read as u64;
+                on_progress(written);
+            }
+
+            file.flush().await?;
+            self.finish_transfer(data_stream).await?;
+            Ok(written)
+        }
+        .await;
+
+        match result {
+            Ok(written) => {
+                tokio::fs::rename(&partial_path, destination).await?;
+                Ok(written)
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    // List a directory. Only regular files are returned.
+    pub async fn list(&mut self, directory: &str) -> Result<Vec<FtpsEntry>, io::Error> {
+        let mut data_stream = self.start_transfer(&format!("LIST {}", directory)).await?;
+        let mut listing = String::new();
+        data_stream.read_to_string(&mut listing).await?;
+        self.finish_transfer(data_stream).await?;
+
+        let directory = directory.trim_end_matches('/');
+        let entries: Vec<FtpsEntry> = listing
+            .lines()
+            .filter_map(|line| FtpsEntry::from_list_line(directory, line))
+            .collect();
+
+        println!(
+            "[BambuFtpsClient::list] Found {} files in {} on {}",
+            entries.len(),
+            directory,
+            self.host
+        );
+
+        Ok(entries)
+    }
+
+    pub async fn delete(&mut self, path: &str) -> Result<(), io::Error> {
+        self.command(&format!("DELE {}", path), &[250]).await?;
+        Ok(())
+    }
+
+    pub async fn quit(mut self) {
+        let _ = self.command("QUIT", &[221]).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn local(
+        year: i32,
+        month: u32,
+        day: u32,
+        hour: u32,
+        minute: u32,
+    ) -> chrono::DateTime<chrono::Local> {
+        chrono::Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_list_times_with_and_without_year() {
+        let now = local(2024, 6, 1, 12, 0);
+
+        assert_eq!(
+            parse_list_time("Mar", "05", "10:21", now),
+            Some(local(2024, 3, 5, 10, 21).timestamp())
+        );
+        assert_eq!(
+            parse_list_time("Mar", "05", "2022", now),
+            Some(local(2022, 3, 5, 0, 0).timestamp())
+        );
+        assert_eq!(parse_list_time("Foo", "05", "2022", now), None);
+    }
+
+    #[test]
+    fn puts_future_list_times_in_the_previous_year() {
+        let now = local(2024, 1, 10, 12, 0);
+
+        assert_eq!(
+            parse_list_time("Dec", "20", "18:30", now),
+            Some(local(2023, 12, 20, 18, 30).timestamp())
+        );
+
+        // A printer clock slightly ahead of ours stays in the current year
+        assert_eq!(
+            parse_list_time("Jan", "10", "13:00", now),
+            Some(local(2024, 1, 10, 13, 0).timestamp())
+        );
+    }
+
+    #[test]
+    fn parses_regular_files_from_list_lines() {
+        let entry = FtpsEntry::from_list_line(
+            "/timelapse",
+            "-rw-rw-rw-   1 root  root  12345678 Mar 05 2023 video 2023-03-05_10-21-33.avi",
+        )
+        .unwrap();
+
+        assert_eq!(entry.name, "video 2023-03-05_10-21-33.avi");
+        assert_eq!(entry.path, "/timelapse/video 2023-03-05_10-21-33.avi");
+        assert_eq!(entry.size, 12345678);
+        assert!(
+            FtpsEntry::from_list_line("/", "drwxrwxrwx 1 root root 0 Mar 05 2023 thumbs").is_none()
+        );
+    }
+}
@@ -0,0 +1,762 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+// Used when the G-code header doesn't say otherwise
+const DEFAULT_FILAMENT_DIAMETER: f64 = 1.75;
+const DEFAULT_FILAMENT_DENSITY: f64 = 1.24;
+const DEFAULT_FEEDRATE: f64 = 1500.0;
+
+// Arcs are split into segments no longer than this (mm)
+const ARC_SEGMENT_LENGTH: f64 = 1.0;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct GcodePath {
+    pub feature: usize, // index into GcodeAnalysis::features
+    pub tool: u32,
+    pub points: Vec<f32>, // flattened [x0, y0, x1, y1, ...]
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct GcodeLayer {
+    pub layer_num: u32, // 1-based, matches the layer_num the printer reports
+    pub z: f32,
+    pub height: f32,
+    pub time_s: f32,
+    pub extruded_mm: f32,
+    pub paths: Vec<GcodePath>,
+    pub svg: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct FilamentUsage {
+    pub tool: u32,
+    pub length_mm: f64,
+    pub weight_g: f64,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct GcodeAnalysis {
+    pub layer_count: u32,
+    pub total_time_s: f64,
+    pub preamble_time_s: f64, // homing, heating, purging etc. before the first layer
+    pub bounds: [f32; 4],     // min x, min y, max x, max y of all extrusions
+    pub features: Vec<String>,
+    pub filament: Vec<FilamentUsage>,
+    pub layers: Vec<GcodeLayer>,
+}
+
+struct ParserState {
+    x: f64,
+    y: f64,
+    z: f64,
+    e: f64,
+    feedrate: f64,
+    absolute_xyz: bool,
+    absolute_e: bool,
+    tool: u32,
+    feature: usize,
+    retracted: HashMap<u32, f64>, // per tool, not yet pushed back by a later extrusion
+}
+
+impl ParserState {
+    // Retractions are remembered and the matching unretract is not counted as extrusion,
+    // so only filament pushed beyond the retracted length is used
+    fn net_extrusion(&mut self, de: f64) -> f64 {
+        let retracted = self.retracted.entry(self.tool).or_insert(0.0);
+        if de < 0.0 {
+            *retracted -= de;
+            return 0.0;
+        }
+
+        let recovered = de.min(*retracted);
+        *retracted -= recovered;
+        de - recovered
+    }
+}
+
+struct AnalysisBuilder {
+    features: Vec<String>,
+    feature_lookup: HashMap<String, usize>,
+    layers: Vec<GcodeLayer>,
+    current_path: Option<GcodePath>,
+    preamble_time_s: f64,
+    extruded_per_tool: HashMap<u32, f64>,
+    diameters: Vec<f64>,
+    densities: Vec<f64>,
+    bounds: [f32; 4],
+}
+
+impl AnalysisBuilder {
+    fn new() -> Self {
+        AnalysisBuilder {
+            features: vec!["Unknown".to_string()],
+            feature_lookup: HashMap::new(),
+            layers: vec![],
+            current_path: None,
+            preamble_time_s: 0.0,
+            extruded_per_tool: HashMap::new(),
+            diameters: vec![],
+            densities: vec![],
+            bounds: [f32::MAX, f32::MAX, f32::MIN, f32::MIN],
+        }
+    }
+
+    fn feature_index(&mut self, feature: &str) -> usize {
+        if let Some(index) = self.feature_lookup.get(feature) {
+            return *index;
+        }
+
+        self.features.push(feature.to_string());
+        self.feature_lookup
+            .insert(feature.to_string(), self.features.len() - 1);
+        self.features.len() - 1
+    }
+
+    fn finish_path(&mut self) {
+        if let Some(path) = self.current_path.take() {
+            if path.points.len() >= 4 {
+                if let Some(layer) = self.layers.last_mut() {
+                    layer.paths.push(path);
+                }
+            }
+        }
+    }
+
+    fn start_layer(&mut self, z: f64) {
+        self.finish_path();
+
+        let previous_z = self.layers.last().map(|l| l.z as f64).unwrap_or(0.0);
+        self.layers.push(GcodeLayer {
+            layer_num: self.layers.len() as u32 + 1,
+            z: z as f32,
+            height: (z - previous_z) as f32,
+            time_s: 0.0,
+            extruded_mm: 0.0,
+            paths: vec![],
+            svg: None,
+        });
+    }
+
+    fn add_time(&mut self, seconds: f64) {
+        match self.layers.last_mut() {
+            Some(layer) => layer.time_s += seconds as f32,
+            None => self.preamble_time_s += seconds,
+        }
+    }
+
+    fn add_extrusion(&mut self, state: &ParserState, from: (f64, f64), to: (f64, f64), de: f64) {
+        *self.extruded_per_tool.entry(state.tool).or_insert(0.0) += de;
+
+        if self.layers.is_empty() {
+            return;
+        }
+
+        // Continue the current polyline if nothing changed, otherwise start a new one
+        let continues = match &self.current_path {
+            Some(path) => {
+                let len = path.points.len();
+                path.feature == state.feature
+                    && path.tool == state.tool
+                    && (path.points[len - 2] - from.0 as f32).abs() < 0.01
+                    && (path.points[len - 1] - from.1 as f32).abs() < 0.01
+            }
+            None => false,
+        };
+
+        if !continues {
+            self.finish_path();
+            self.current_path = Some(GcodePath {
+                feature: state.feature,
+                tool: state.tool,
+                points: vec![round(from.0), round(from.1)],
+            });
+        }
+
+        if let Some(path) = self.current_path.as_mut() {
+            path.points.push(round(to.0));
+            path.points.push(round(to.1));
+        }
+
+        for (x, y) in [from, to] {
+            self.bounds[0] = self.bounds[0].min(x as f32);
+            self.bounds[1] = self.bounds[1].min(y as f32);
+            self.bounds[2] = self.bounds[2].max(x as f32);
+            self.bounds[3] = self.bounds[3].max(y as f32);
+        }
+
+        if let Some(layer) = self.layers.last_mut() {
+            layer.extruded_mm += de as f32;
+        }
+    }
+}
+
+fn round(value: f64) -> f32 {
+    ((value * 100.0).round() / 100.0) as f32
+}
+
+// Parse a comma separated list of numbers from a header value ("1.75,1.75")
+fn parse_number_list(value: &str) -> Vec<f64> {
+    value
+        .split(',')
+        .filter_map(|v| v.trim().parse::<f64>().ok())
+        .collect()
+}
+
+// Split a line into its code and the trimmed text after ';'
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.find(';') {
+        Some(index) => (&line[..index], Some(line[index + 1..].trim())),
+        None => (line, None),
+    }
+}
+
+fn is_layer_marker(comment: &str) -> bool {
+    comment == "CHANGE_LAYER"
+}
+
+// Split "; key: value" / "; key = value" header comments
+fn parse_header_comment(comment: &str) -> Option<(String, &str)> {
+    let split_at = comment.find([':', '='])?;
+    let key = comment[..split_at].trim().to_lowercase();
+    let value = comment[split_at + 1..].trim();
+    Some((key, value))
+}
+
+fn parse_word(word: &str) -> Option<(char, f64)> {
+    let mut chars = word.chars();
+    let letter = chars.next()?.to_ascii_uppercase();
+    let value = chars.as_str().parse::<f64>().ok()?;
+    Some((letter, value))
+}
+
+pub fn analyze(gcode: &str, include_svg: bool) -> GcodeAnalysis {
+    let mut builder = AnalysisBuilder::new();
+    let mut state = ParserState {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+        e: 0.0,
+        feedrate: DEFAULT_FEEDRATE,
+        absolute_xyz: true,
+        absolute_e: true,
+        tool: 0,
+        feature: 0,
+        retracted: HashMap::new(),
+    };
+
+    // Bambu Studio marks layer changes explicitly, other slicers only change Z
+    let has_layer_markers = gcode
+        .lines()
+        .any(|line| matches!(split_comment(line).1, Some(comment) if is_layer_marker(comment)));
+    let mut pending_layer = false;
+    let mut awaiting_layer_height = false;
+
+    for raw_line in gcode.lines() {
+        let (code, comment) = split_comment(raw_line);
+
+        if let Some(comment) = comment {
+            if is_layer_marker(comment) {
+                pending_layer = true;
+            } else if let Some((key, value)) = parse_header_comment(comment) {
+                match key.as_str() {
+                    "z_height" if pending_layer => {
+                        if let Ok(z) = value.parse::<f64>() {
+                            builder.start_layer(z);
+                            pending_layer = false;
+                            awaiting_layer_height = true;
+                        }
+                    }
+                    "layer_height" if awaiting_layer_height => {
+                        if let (Ok(height), Some(layer)) =
+                            (value.parse::<f32>(), builder.layers.last_mut())
+                        {
+                            layer.height = height;
+                        }
+                        awaiting_layer_height = false;
+                    }
+                    "feature" | "type" => {
+                        builder.finish_path();
+                        state.feature = builder.feature_index(value);
+                    }
+                    "filament_diameter" if builder.diameters.is_empty() => {
+                        builder.diameters = parse_number_list(value);
+                    }
+                    "filament_density" if builder.densities.is_empty() => {
+                        builder.densities = parse_number_list(value);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut words = code.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command.to_uppercase(),
+            None => continue,
+        };
+        let params: Vec<(char, f64)> = words.filter_map(parse_word).collect();
+        let param = |letter: char| params.iter().find(|(l, _)| *l == letter).map(|(_, v)| *v);
+
+        match command.as_str() {
+            "G0" | "G1" | "G2" | "G3" => {
+                if let Some(f) = param('F') {
+                    if f > 0.0 {
+                        state.feedrate = f;
+                    }
+                }
+
+                let resolve = |current: f64, value: Option<f64>| match value {
+                    Some(v) if state.absolute_xyz => v,
+                    Some(v) => current + v,
+                    None => current,
+                };
+
+                let target_x = resolve(state.x, param('X'));
+                let target_y = resolve(state.y, param('Y'));
+                let target_z = resolve(state.z, param('Z'));
+                let de = match param('E') {
+                    Some(e) if state.absolute_e => e - state.e,
+                    Some(e) => e,
+                    None => 0.0,
+                };
+                let extruded = state.net_extrusion(de);
+
+                // A layer marker without a Z_HEIGHT comment starts at the next Z move
+                if pending_layer && param('Z').is_some() {
+                    builder.start_layer(target_z);
+                    pending_layer = false;
+                }
+
+                // Without layer markers, the first extrusion at a new height starts a layer
+                if !has_layer_markers && extruded > 0.0 {
+                    let current_z = builder.layers.last().map(|l| l.z as f64);
+                    if current_z
+                        .map(|z| (z - target_z).abs() > 1e-4)
+                        .unwrap_or(true)
+                    {
+                        builder.start_layer(target_z);
+                    }
+                }
+
+                let mut points = vec![(target_x, target_y)];
+                if command == "G2" || command == "G3" {
+                    if let (Some(i), Some(j)) = (param('I'), param('J')) {
+                        points = arc_points(
+                            (state.x, state.y),
+                            (target_x, target_y),
+                            (state.x + i, state.y + j),
+                            command == "G2",
+                        );
+                    }
+                }
+
+                let mut distance = 0.0;
+                let mut from = (state.x, state.y);
+                let segment_de = extruded / points.len() as f64;
+                for to in points {
+                    distance += ((to.0 - from.0).powi(2) + (to.1 - from.1).powi(2)).sqrt();
+
+                    if segment_de > 0.0 {
+                        builder.add_extrusion(&state, from, to, segment_de);
+                    }
+
+                    from = to;
+                }
+
+                if extruded <= 0.0 {
+                    builder.finish_path();
+                }
+
+                distance = (distance.powi(2) + (target_z - state.z).powi(2)).sqrt();
+                if distance == 0.0 {
+                    distance = de.abs();
+                }
+
+                // Plain kinematic estimate, acceleration is ignored
+                builder.add_time(distance / (state.feedrate / 60.0));
+
+                state.x = target_x;
+                state.y = target_y;
+                state.z = target_z;
+                if state.absolute_e {
+                    state.e += de;
+                }
+            }
+            "G4" => {
+                let seconds = param('S').unwrap_or(0.0) + param('P').unwrap_or(0.0) / 1000.0;
+                builder.add_time(seconds);
+            }
+            "G28" => {
+                state.x = 0.0;
+                state.y = 0.0;
+                state.z = 0.0;
+            }
+            "G90" => state.absolute_xyz = true,
+            "G91" => state.absolute_xyz = false,
+            "M82" => state.absolute_e = true,
+            "M83" => state.absolute_e = false,
+            "G92" => {
+                if let Some(e) = param('E') {
+                    state.e = e;
+                }
+                if let Some(x) = param('X') {
+                    state.x = x;
+                }
+                if let Some(y) = param('Y') {
+                    state.y = y;
+                }
+                if let Some(z) = param('Z') {
+                    state.z = z;
+                }
+            }
+            _ => {
+                // Tool changes, T255 and T1000 are used by Bambu for unload/special moves
+                if let Some(tool) = command.strip_prefix('T') {
+                    if let Ok(tool) = tool.parse::<u32>() {
+                        if tool < 254 {
+                            builder.finish_path();
+                            state.tool = tool;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    builder.finish_path();
+
+    let mut filament: Vec<FilamentUsage> = builder
+        .extruded_per_tool
+        .iter()
+        .map(|(tool, length)| {
+            let diameter = builder
+                .diameters
+                .get(*tool as usize)
+                .or(builder.diameters.first())
+                .copied()
+                .unwrap_or(DEFAULT_FILAMENT_DIAMETER);
+            let density = builder
+                .densities
+                .get(*tool as usize)
+                .or(builder.densities.first())
+                .copied()
+                .unwrap_or(DEFAULT_FILAMENT_DENSITY);
+
+            // mm * mm^2 = mm^3, / 1000 = cm^3, * g/cm^3 = g
+            let volume = length * std::f64::consts::PI * (diameter / 2.0).powi(2) / 1000.0;
+
+            FilamentUsage {
+                tool: *tool,
+                length_mm: *length,
+                weight_g: volume * density,
+            }
+        })
+        .collect();
+    filament.sort_by_key(|f| f.tool);
+
+    if builder.bounds[0] > builder.bounds[2] {
+        builder.bounds = [0.0; 4];
+    }
+
+    if include_svg {
+        let bounds = builder.bounds;
+        for layer in builder.layers.iter_mut() {
+            layer.svg = Some(layer_svg(layer, &builder.features, bounds));
+        }
+    }
+
+    let total_time_s =
+        builder.preamble_time_s + builder.layers.iter().map(|l| l.time_s as f64).sum::<f64>();
+
+    GcodeAnalysis {
+        layer_count: builder.layers.len() as u32,
+        total_time_s,
+        preamble_time_s: builder.preamble_time_s,
+        bounds: builder.bounds,
+        features: builder.features,
+        filament,
+        layers: builder.layers,
+    }
+}
+
+// Linearize a G2/G3 arc into points, excluding the start and including the end
+fn arc_points(
+    start: (f64, f64),
+    end: (f64, f64),
+    center: (f64, f64),
+    clockwise: bool,
+) -> Vec<(f64, f64)> {
+    let radius = ((start.0 - center.0).powi(2) + (start.1 - center.1).powi(2)).sqrt();
+    let start_angle = (start.1 - center.1).atan2(start.0 - center.0);
+    let end_angle = (end.1 - center.1).atan2(end.0 - center.0);
+
+    let mut sweep = end_angle - start_angle;
+    if clockwise && sweep >= 0.0 {
+        sweep -= 2.0 * std::f64::consts::PI;
+    } else if !clockwise && sweep <= 0.0 {
+        sweep += 2.0 * std::f64::consts::PI;
+    }
+
+    let segments = ((sweep.abs() * radius) / ARC_SEGMENT_LENGTH)
+        .ceil()
+        .max(1.0) as usize;
+    let mut points = vec![];
+    for i in 1..segments {
+        let angle = start_angle + sweep * (i as f64 / segments as f64);
+        points.push((
+            center.0 + radius * angle.cos(),
+            center.1 + radius * angle.sin(),
+        ));
+    }
+
+    points.push(end);
+    points
+}
+
+fn feature_color(feature: &str) -> &'static str {
+    match feature.to_lowercase().as_str() {
+        "outer wall" | "external perimeter" => "#ff7f0e",
+        "inner wall" | "perimeter" => "#ffbb78",
+        "overhang wall" | "overhang perimeter" => "#1f77b4",
+        "sparse infill" | "internal infill" => "#d62728",
+        "internal solid infill" | "solid infill" => "#9467bd",
+        "top surface" | "top solid infill" => "#e377c2",
+        "bottom surface" => "#8c564b",
+        "bridge" | "bridge infill" => "#17becf",
+        "support" | "support material" => "#2ca02c",
+        "support interface" | "support material interface" => "#98df8a",
+        "skirt" | "brim" | "skirt/brim" => "#7f7f7f",
+        "prime tower" | "wipe tower" => "#bcbd22",
+        _ => "#aaaaaa",
+    }
+}
+
+// Render a layer as an SVG in bed coordinates, using the bounds of the whole print so layers line up
+pub fn layer_svg(layer: &GcodeLayer, features: &[String], bounds: [f32; 4]) -> String {
+    let width = (bounds[2] - bounds[0]).max(1.0);
+    let height = (bounds[3] - bounds[1]).max(1.0);
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\"><g transform=\"scale(1,-1)\" fill=\"none\" stroke-width=\"0.4\" stroke-linecap=\"round\" stroke-linejoin=\"round\">",
+        bounds[0],
+        -bounds[3],
+        width,
+        height
+    );
+
+    for path in layer.paths.iter() {
+        let feature = features.get(path.feature).map(|f| f.as_str()).unwrap_or("");
+        let points = path
+            .points
+            .chunks(2)
+            .map(|p| format!("{},{}", p[0], p[1]))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        svg.push_str(&format!(
+            "<polyline stroke=\"{}\" points=\"{}\"/>",
+            feature_color(feature),
+            points
+        ));
+    }
+
+    svg.push_str("</g></svg>");
+    svg
+}
+
+// Pull a plate's G-code out of a 3MF project (Metadata/plate_<n>.gcode)
+pub fn extract_from_3mf(data: Vec<u8>, plate: Option<u32>) -> Result<String, io::Error> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to open 3MF archive: {}", e),
+        )
+    })?;
+
+    let name = match plate {
+        Some(plate) => format!("Metadata/plate_{}.gcode", plate),
+        None => {
+            // Default to the first sliced plate in the project
+            let mut plates: Vec<String> = archive
+                .file_names()
+                .filter(|n| n.starts_with("Metadata/plate_") && n.ends_with(".gcode"))
+                .map(|n| n.to_string())
+                .collect();
+            plates.sort();
+
+            plates.into_iter().next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The 3MF does not contain any sliced plates",
+                )
+            })?
+        }
+    };
+
+    let mut file = archive.by_name(&name).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Failed to find {} in 3MF archive: {}", name, e),
+        )
+    })?;
+
+    let mut gcode = String::new();
+    file.read_to_string(&mut gcode)?;
+    Ok(gcode)
+}
+
+// Read G-code either from a plain .gcode file or from a plate inside a .3mf
+pub fn load_gcode(data: Vec<u8>, path: &str, plate: Option<u32>) -> Result<String, io::Error> {
+    if path.to_lowercase().ends_with(".3mf") {
+        extract_from_3mf(data, plate)
+    } else {
+        String::from_utf8(data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("G-code file {} is not valid UTF-8: {}", path, e),
+            )
+        })
+    }
+}
+
+pub fn load_local_gcode(path: &Path, plate: Option<u32>) -> Result<String, io::Error> {
+    let data = std::fs::read(path)?;
+    load_gcode(data, &path.to_string_lossy(), plate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BAMBU_GCODE: &str = "\
+; filament_diameter: 1.75,1.75
+; filament_density: 1.24,1.27
+G28
+G90
+M83
+G1 X10 Y10 F6000
+; CHANGE_LAYER
+; Z_HEIGHT: 0.2
+; LAYER_HEIGHT: 0.2
+G1 Z0.2
+; FEATURE: Outer wall
+G1 X20 Y10 E1.0 F600
+G1 X20 Y20 E1.0
+G1 X30 Y30
+; CHANGE_LAYER
+; Z_HEIGHT: 0.4
+; LAYER_HEIGHT: 0.2
+G1 Z0.4
+T1
+; FEATURE: Sparse infill
+G1 X40 Y30 E2.0
+";
+
+    #[test]
+    fn splits_layers_on_bambu_markers() {
+        let analysis = analyze(BAMBU_GCODE, false);
+
+        assert_eq!(analysis.layer_count, 2);
+        assert_eq!(analysis.layers[0].z, 0.2);
+        assert_eq!(analysis.layers[1].height, 0.2);
+        assert_eq!(analysis.layers[0].extruded_mm, 2.0);
+        assert!(analysis.preamble_time_s > 0.0);
+        assert_eq!(analysis.bounds, [10.0, 10.0, 40.0, 30.0]);
+    }
+
+    #[test]
+    fn joins_continuous_extrusions_into_one_path() {
+        let analysis = analyze(BAMBU_GCODE, false);
+        let paths = &analysis.layers[0].paths;
+
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].points, vec![10.0, 10.0, 20.0, 10.0, 20.0, 20.0]);
+        assert_eq!(analysis.features[paths[0].feature], "Outer wall");
+    }
+
+    #[test]
+    fn tracks_filament_per_tool() {
+        let analysis = analyze(BAMBU_GCODE, false);
+
+        assert_eq!(analysis.filament.len(), 2);
+        assert_eq!(analysis.filament[1].tool, 1);
+        assert_eq!(analysis.filament[1].length_mm, 2.0);
+
+        // 2 mm of 1.75 mm filament at 1.27 g/cm^3
+        let expected = 2.0 * std::f64::consts::PI * 0.875_f64.powi(2) / 1000.0 * 1.27;
+        assert!((analysis.filament[1].weight_g - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unretracting_does_not_count_as_extrusion() {
+        let gcode = "G90\nM83\nG1 Z0.2 X0 Y0\nG1 X10 E1\nG1 E-0.8\nG1 X20\nG1 E0.8\nG1 X30 E1.5\n";
+        let analysis = analyze(gcode, false);
+
+        // 1 mm before the retraction and 1.5 mm after it, the retract/unretract pair cancels out
+        assert_eq!(analysis.filament[0].length_mm, 2.5);
+        assert_eq!(analysis.layers[0].extruded_mm, 2.5);
+        assert_eq!(analysis.layers[0].paths.len(), 2);
+    }
+
+    #[test]
+    fn extrusion_beyond_a_retraction_is_counted() {
+        let gcode = "G90\nM82\nG1 Z0.2 X0 Y0\nG1 X10 E1\nG1 E0.5\nT1\nG1 X20 E1.2\nT0\nG1 X30 E2\n";
+        let analysis = analyze(gcode, false);
+
+        // T1 has no pending retraction, T0 pushes back 0.5 mm before extruding again
+        assert!((analysis.filament[0].length_mm - 1.3).abs() < 1e-9);
+        assert!((analysis.filament[1].length_mm - 0.7).abs() < 1e-9);
+    }
+
+    #[test]
+    fn detects_layer_markers_without_a_space() {
+        let gcode =
+            "G90\nM83\n;CHANGE_LAYER\n;Z_HEIGHT: 0.2\nG1 Z0.2\nG1 X10 E1\nG1 Z0.6 X0\nG1 X5 E1\n";
+        let analysis = analyze(gcode, false);
+
+        // With markers present, a Z change alone doesn't start a new layer
+        assert_eq!(analysis.layer_count, 1);
+        assert_eq!(analysis.layers[0].z, 0.2);
+        assert_eq!(analysis.layers[0].extruded_mm, 2.0);
+    }
+
+    #[test]
+    fn starts_layers_on_z_changes_without_markers() {
+        let gcode = "G90\nM82\nG1 Z0.3 X0 Y0\nG1 X5 E1\nG1 Z0.6\nG1 X0 E2\nG1 Z0.9\n";
+        let analysis = analyze(gcode, true);
+
+        assert_eq!(analysis.layer_count, 2);
+        assert_eq!(analysis.layers[1].z, 0.6);
+        assert!((analysis.layers[1].height - 0.3).abs() < 1e-6);
+        assert!(analysis.layers[0]
+            .svg
+            .as_deref()
+            .unwrap()
+            .contains("<polyline"));
+    }
+
+    #[test]
+    fn linearizes_arcs_into_short_segments() {
+        let points = arc_points((10.0, 0.0), (-10.0, 0.0), (0.0, 0.0), false);
+
+        // Half a circle with radius 10 is about 31 mm
+        assert_eq!(points.len(), 32);
+        assert_eq!(points.last(), Some(&(-10.0, 0.0)));
+        assert!(points.iter().all(|(_, y)| *y >= 0.0));
+    }
+
+    #[test]
+    fn reads_header_comments() {
+        assert_eq!(
+            parse_header_comment("Z_HEIGHT: 0.2"),
+            Some(("z_height".to_string(), "0.2"))
+        );
+        assert_eq!(
+            parse_header_comment("filament_density = 1.24,1.27"),
+            Some(("filament_density".to_string(), "1.24,1.27"))
+        );
+        assert_eq!(parse_number_list("1.75, x,2.85"), vec![1.75, 2.85]);
+        assert_eq!(parse_word("x12.5"), Some(('X', 12.5)));
+    }
+}
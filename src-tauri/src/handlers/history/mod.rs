@@ -0,0 +1,80 @@
+use super::bambu::{BambuTask, BambuTaskPage};
+use super::config::get_config_dir;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+// Plenty for a history view, older tasks can still be fetched from the cloud
+const MAX_CACHED_TASKS: usize = 2000;
+
+// Tasks fetched from the cloud, kept so the history view works offline
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct TaskHistoryCache {
+    pub updated_at: i64,
+    pub tasks: Vec<BambuTask>,
+}
+
+fn get_task_history_path() -> io::Result<PathBuf> {
+    let mut path = get_config_dir()?;
+    path.push("task_history.json");
+    Ok(path)
+}
+
+impl TaskHistoryCache {
+    pub fn load() -> io::Result<Self> {
+        let path = get_task_history_path()?;
+        if !path.exists() {
+            return Ok(TaskHistoryCache::default());
+        }
+
+        serde_json::from_str(std::fs::read_to_string(path)?.as_str()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Could not parse task history cache: {}", e),
+            )
+        })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut file = File::create(get_task_history_path()?)?;
+        let json = serde_json::to_string_pretty(&self)?;
+        file.write_all(json.as_bytes())?;
+        Ok(())
+    }
+
+    // Add or update tasks, keeping the newest first
+    pub fn merge(&mut self, tasks: &[BambuTask]) {
+        for task in tasks {
+            match self.tasks.iter_mut().find(|t| t.id == task.id) {
+                Some(existing) => *existing = task.clone(),
+                None => self.tasks.push(task.clone()),
+            }
+        }
+
+        // Start times are ISO 8601 in UTC, so they sort as strings
+        self.tasks
+            .sort_by(|a, b| b.start_time.cmp(&a.start_time).then(b.id.cmp(&a.id)));
+        self.tasks.truncate(MAX_CACHED_TASKS);
+        self.updated_at = chrono::Local::now().timestamp();
+    }
+
+    // The same page the cloud would have returned, from whatever we have cached
+    pub fn page(&self, device_id: Option<&str>, offset: u32, limit: u32) -> BambuTaskPage {
+        let matching: Vec<&BambuTask> = self
+            .tasks
+            .iter()
+            .filter(|t| device_id.map(|id| t.device_id == id).unwrap_or(true))
+            .collect();
+
+        BambuTaskPage {
+            total: matching.len() as i64,
+            tasks: matching
+                .into_iter()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect(),
+        }
+    }
+}
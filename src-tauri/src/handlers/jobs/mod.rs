@@ -0,0 +1,303 @@
+use super::config::get_config_dir;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// Only the most recent jobs are kept in the log
+const MAX_JOB_RECORDS: usize = 500;
+
+lazy_static! {
+    static ref JOB_LOG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JobRecord {
+    pub id: String,
+    pub dev_id: String,
+    pub task_id: String,
+    pub subtask_name: String,
+    pub gcode_file: String,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub status: String, // running, finish, failed or cancelled
+}
+
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    Started(JobRecord),
+    Resumed(JobRecord, u32), // Already running when we connected, with the percent at that point
+    Progress(JobRecord, u32),
+    LayerChanged(JobRecord, u32),
+    Hms(Option<JobRecord>, Vec<String>), // HMS errors can also fire while idle
+    Finished(JobRecord),
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct JobLog {
+    pub jobs: Vec<JobRecord>,
+}
+
+fn get_job_log_path() -> io::Result<PathBuf> {
+    let mut path = get_config_dir()?;
+    path.push("jobs.json");
+    Ok(path)
+}
+
+impl JobLog {
+    pub fn load() -> io::Result<Self> {
+        let path = get_job_log_path()?;
+        if !path.exists() {
+            return Ok(JobLog::default());
+        }
+
+        serde_json::from_str(std::fs::read_to_string(path)?.as_str()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Could not parse job log: {}", e),
+            )
+        })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut file = File::create(get_job_log_path()?)?;
+        let json = serde_json::to_string_pretty(&self)?;
+        file.write_all(json.as_bytes())?;
+        Ok(())
+    }
+
+    fn upsert(&mut self, record: &JobRecord) {
+        match self.jobs.iter_mut().find(|j| j.id == record.id) {
+            Some(existing) => *existing = record.clone(),
+            None => self.jobs.push(record.clone()),
+        }
+
+        if self.jobs.len() > MAX_JOB_RECORDS {
+            let excess = self.jobs.len() - MAX_JOB_RECORDS;
+            self.jobs.drain(..excess);
+        }
+    }
+
+    // Find the job a device was running at the given time, with some slack on both ends
+    // since recordings start a little before and end a little after the job itself
+    pub fn find_for_timestamp(&self, dev_id: &str, timestamp: i64) -> Option<&JobRecord> {
+        const SLACK_SECS: i64 = 10 * 60;
+        let now = chrono::Local::now().timestamp();
+
+        self.jobs
+            .iter()
+            .filter(|j| j.dev_id == dev_id)
+            .filter(|j| {
+                timestamp >= j.started_at - SLACK_SECS
+                    && timestamp <= j.finished_at.unwrap_or(now) + SLACK_SECS
+            })
+            .min_by_key(|j| (j.started_at - timestamp).abs())
+    }
+}
+
+// What a report changed. The records are left for the caller to save once it has let go of
+// its locks, see save_records
+#[derive(Default)]
+pub struct JobObservation {
+    pub events: Vec<JobEvent>,
+    pub records: Vec<JobRecord>,
+}
+
+// Follows gcode_state in device reports to find out when jobs start and end
+#[derive(Default)]
+pub struct JobTracker {
+    devices: HashMap<String, DeviceJobState>,
+}
+
+#[derive(Default)]
+struct DeviceJobState {
+    gcode_state: Option<String>,
+    active_job: Option<JobRecord>,
+    percent: Option<u32>,
+    layer_num: Option<u32>,
+    hms_codes: Vec<String>,
+}
+
+fn is_active_state(state: &str) -> bool {
+    matches!(state, "PREPARE" | "RUNNING" | "PAUSE" | "SLICING")
+}
+
+// Some fields are sent as strings by one firmware and as numbers by another
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+// HMS entries are reported as two numbers, shown to users as HMS_AAAA_BBBB_CCCC_DDDD
+fn parse_hms_codes(print: &Value) -> Vec<String> {
+    print["hms"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let attr = entry["attr"].as_u64()?;
+            let code = entry["code"].as_u64()?;
+            Some(format!(
+                "HMS_{:04X}_{:04X}_{:04X}_{:04X}",
+                (attr >> 16) & 0xFFFF,
+                attr & 0xFFFF,
+                (code >> 16) & 0xFFFF,
+                code & 0xFFFF
+            ))
+        })
+        .collect()
+}
+
+// Write job records to the log without blocking the MQTT tasks. Several devices can report at
+// once, so updates to the file are serialized
+pub async fn save_records(records: Vec<JobRecord>) {
+    if records.is_empty() {
+        return;
+    }
+
+    let result = tokio::task::spawn_blocking(move || {
+        let _guard = JOB_LOG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut log = JobLog::load()?;
+        for record in records.iter() {
+            log.upsert(record);
+        }
+        log.save()
+    })
+    .await
+    .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e.to_string())));
+
+    if let Err(e) = result {
+        println!("[jobs::save_records] Failed to update job log: {}", e);
+    }
+}
+
+impl JobTracker {
+    pub fn new() -> JobTracker {
+        JobTracker {
+            devices: HashMap::new(),
+        }
+    }
+
+    pub fn observe(&mut self, dev_id: &str, report: &Value) -> JobObservation {
+        let print = &report["print"];
+        let device = self.devices.entry(dev_id.to_string()).or_default();
+        let mut events = vec![];
+        let mut records = vec![];
+
+        if let Some(state) = print["gcode_state"].as_str() {
+            let previous_state = device.gcode_state.replace(state.to_string());
+            let was_active = previous_state
+                .as_deref()
+                .map(is_active_state)
+                .unwrap_or(false);
+
+            if is_active_state(state) && !was_active {
+                let now = chrono::Local::now().timestamp();
+
+                // When we connect mid-job, the printer still tells us when it started
+                let started_at = value_to_string(&print["gcode_start_time"])
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|t| *t > 0)
+                    .unwrap_or(now);
+
+                let record = JobRecord {
+                    id: format!("{}-{}", dev_id, started_at),
+                    dev_id: dev_id.to_string(),
+                    task_id: value_to_string(&print["task_id"]),
+                    subtask_name: value_to_string(&print["subtask_name"]),
+                    gcode_file: value_to_string(&print["gcode_file"]),
+                    started_at,
+                    finished_at: None,
+                    status: "running".to_string(),
+                };
+
+                records.push(record.clone());
+                device.active_job = Some(record.clone());
+                device.layer_num = None;
+
+                // Without a previous state we connected to a job that was already running
+                if previous_state.is_none() {
+                    println!(
+                        "[JobTracker::observe] Job {} was already running on device: {}",
+                        record.id, dev_id
+                    );
+
+                    // Progress is only reported from here on, not for the percent we joined at
+                    let percent = print["mc_percent"].as_u64().map(|p| p as u32);
+                    device.percent = percent;
+                    events.push(JobEvent::Resumed(record, percent.unwrap_or(0)));
+                } else {
+                    println!(
+                        "[JobTracker::observe] Job {} started on device: {}",
+                        record.id, dev_id
+                    );
+
+                    device.percent = None;
+                    events.push(JobEvent::Started(record));
+                }
+            } else if !is_active_state(state) && was_active {
+                if let Some(mut record) = device.active_job.take() {
+                    record.finished_at = Some(chrono::Local::now().timestamp());
+                    record.status = match state {
+                        "FINISH" => "finish",
+                        "FAILED" => "failed",
+                        _ => "cancelled",
+                    }
+                    .to_string();
+
+                    println!(
+                        "[JobTracker::observe] Job {} on device: {} is now {}",
+                        record.id, dev_id, record.status
+                    );
+
+                    records.push(record.clone());
+                    events.push(JobEvent::Finished(record));
+                }
+            }
+        }
+
+        if let Some(record) = &device.active_job {
+            if let Some(percent) = print["mc_percent"].as_u64().map(|p| p as u32) {
+                if device.percent != Some(percent) {
+                    device.percent = Some(percent);
+                    events.push(JobEvent::Progress(record.clone(), percent));
+                }
+            }
+
+            // Layer 0 is reported while heating and levelling, before anything is printed
+            if let Some(layer_num) = print["layer_num"].as_u64().map(|l| l as u32) {
+                if layer_num > 0 && device.layer_num != Some(layer_num) {
+                    device.layer_num = Some(layer_num);
+                    events.push(JobEvent::LayerChanged(record.clone(), layer_num));
+                }
+            }
+        }
+
+        // Only codes that weren't active on the last report are new errors
+        let hms_codes = parse_hms_codes(print);
+        let new_codes: Vec<String> = hms_codes
+            .iter()
+            .filter(|code| !device.hms_codes.contains(code))
+            .cloned()
+            .collect();
+        device.hms_codes = hms_codes;
+
+        if !new_codes.is_empty() {
+            println!(
+                "[JobTracker::observe] New HMS errors on device: {}: {:?}",
+                dev_id, new_codes
+            );
+            events.push(JobEvent::Hms(device.active_job.clone(), new_codes));
+        }
+
+        JobObservation { events, records }
+    }
+}
@@ -0,0 +1,302 @@
+use super::bambu::BambuDevice;
+use super::ftps::BambuFtpsClient;
+use super::jobs::{JobLog, JobRecord};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Manager;
+use tokio::sync::{watch, Mutex};
+
+// Directories on the printer's SD card and the kind of video they hold
+const MEDIA_DIRECTORIES: [(&str, &str); 2] = [("/timelapse", "timelapse"), ("/ipcam", "recording")];
+const VIDEO_EXTENSIONS: [&str; 3] = [".mp4", ".avi", ".mkv"];
+
+// Don't flood the frontend, one progress event per file every so often is plenty
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct MediaFile {
+    pub kind: String,
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub recorded_at: Option<i64>,
+    pub job: Option<JobRecord>,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct MediaDownloadProgress {
+    pub download_id: u32,
+    pub dev_id: String,
+    pub path: String,
+    pub local_path: String,
+    pub downloaded: u64,
+    pub total: u64,
+    pub done: bool,
+    pub deleted: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, Clone)]
+pub struct MediaDownloadFinished {
+    pub download_id: u32,
+    pub dev_id: String,
+    pub downloaded: Vec<String>,
+    pub failed: Vec<String>,
+    pub cancelled: bool,
+}
+
+// Recordings are named after the time they started, e.g. video_2024-03-05_10-21-33.avi
+fn parse_name_timestamp(name: &str) -> Option<i64> {
+    use chrono::TimeZone;
+
+    const PATTERN_LEN: usize = "2024-03-05_10-21-33".len();
+    let bytes = name.as_bytes();
+
+    for start in 0..bytes.len().saturating_sub(PATTERN_LEN - 1) {
+        let candidate = match name.get(start..start + PATTERN_LEN) {
+            Some(candidate) => candidate,
+            None => continue,
+        };
+
+        if let Ok(time) = chrono::NaiveDateTime::parse_from_str(candidate, "%Y-%m-%d_%H-%M-%S") {
+            // The printer's clock follows the timezone configured on it, assume it matches ours
+            return chrono::Local
+                .from_local_datetime(&time)
+                .earliest()
+                .map(|t| t.timestamp());
+        }
+    }
+
+    None
+}
+
+pub async fn list_media(device: &BambuDevice) -> Result<Vec<MediaFile>, io::Error> {
+    let mut client = BambuFtpsClient::connect(device).await?;
+    let mut files = vec![];
+
+    for (directory, kind) in MEDIA_DIRECTORIES {
+        // Not every model has both directories, so a failing listing isn't fatal
+        let entries = match client.list(directory).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!(
+                    "[media::list_media] Failed to list {} on device: {}: {}",
+                    directory, device.name, e
+                );
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let name = entry.name.to_lowercase();
+            if !VIDEO_EXTENSIONS.iter().any(|ext| name.ends_with(ext)) {
+                continue;
+            }
+
+            files.push(MediaFile {
+                kind: kind.to_string(),
+                recorded_at: parse_name_timestamp(&entry.name).or(entry.modified),
+                name: entry.name,
+                path: entry.path,
+                size: entry.size,
+                job: None,
+            });
+        }
+    }
+
+    client.quit().await;
+
+    // Match each video to the job that was running when it was recorded
+    match JobLog::load() {
+        Ok(log) => {
+            for file in files.iter_mut() {
+                if let Some(recorded_at) = file.recorded_at {
+                    file.job = log.find_for_timestamp(&device.dev_id, recorded_at).cloned();
+                }
+            }
+        }
+        Err(e) => println!("[media::list_media] Failed to load job log: {}", e),
+    }
+
+    files.sort_by_key(|f| std::cmp::Reverse(f.recorded_at));
+    Ok(files)
+}
+
+// Strip anything that can't be used in a folder name
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+pub struct MediaDownloadManager {
+    next_id: u32,
+    downloads: Arc<Mutex<HashMap<u32, watch::Sender<bool>>>>, // Cancels the download when set
+}
+
+impl MediaDownloadManager {
+    pub fn new() -> MediaDownloadManager {
+        MediaDownloadManager {
+            next_id: 1,
+            downloads: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Download files in the background, emitting media-download-progress events as they go
+    // and media-download-finished once all of them are done
+    pub async fn start(
+        &mut self,
+        app_handle: tauri::AppHandle,
+        device: BambuDevice,
+        files: Vec<MediaFile>,
+        destination: PathBuf,
+        delete_after: bool,
+    ) -> u32 {
+        let download_id = self.next_id;
+        self.next_id += 1;
+
+        let downloads = self.downloads.clone();
+        let (cancel_sender, mut cancel) = watch::channel(false);
+        self.downloads
+            .lock()
+            .await
+            .insert(download_id, cancel_sender);
+
+        tokio::spawn(async move {
+            let device_dir = destination.join(sanitize_file_name(&device.name));
+            let mut finished = MediaDownloadFinished {
+                download_id,
+                dev_id: device.dev_id.clone(),
+                downloaded: vec![],
+                failed: vec![],
+                cancelled: false,
+            };
+
+            let result: Result<(), io::Error> = async {
+                tokio::fs::create_dir_all(&device_dir).await?;
+                let mut client = BambuFtpsClient::connect(&device).await?;
+
+                for file in files {
+                    if *cancel.borrow() {
+                        finished.cancelled = true;
+                        break;
+                    }
+
+                    let local_path = device_dir.join(sanitize_file_name(&file.name));
+                    let mut progress = MediaDownloadProgress {
+                        download_id,
+                        dev_id: device.dev_id.clone(),
+                        path: file.path.clone(),
+                        local_path: local_path.to_string_lossy().to_string(),
+                        downloaded: 0,
+                        total: file.size,
+                        done: false,
+                        deleted: false,
+                        error: None,
+                    };
+
+                    let mut last_emit = Instant::now();
+                    let result = client
+                        .retrieve_to_file(&file.path, &local_path, &mut cancel, |downloaded| {
+                            if last_emit.elapsed() >= PROGRESS_INTERVAL {
+                                progress.downloaded = downloaded;
+                                let _ = app_handle.emit_all("media-download-progress", &progress);
+                                last_emit = Instant::now();
+                            }
+                        })
+                        .await;
+
+                    match result {
+                        Ok(downloaded) => {
+                            progress.downloaded = downloaded;
+
+                            if delete_after {
+                                match client.delete(&file.path).await {
+                                    Ok(_) => progress.deleted = true,
+                                    Err(e) => {
+                                        println!(
+                                            "[MediaDownloadManager::task::download] Failed to delete {} from device: {}: {}",
+                                            file.path, device.name, e
+                                        );
+                                    }
+                                }
+                            }
+
+                            finished.downloaded.push(file.path.clone());
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                            finished.cancelled = true;
+                        }
+                        Err(e) => {
+                            println!(
+                                "[MediaDownloadManager::task::download] Failed to download {} from device: {}: {}",
+                                file.path, device.name, e
+                            );
+
+                            progress.error = Some(e.to_string());
+                            finished.failed.push(file.path.clone());
+                        }
+                    }
+
+                    progress.done = true;
+                    let _ = app_handle.emit_all("media-download-progress", &progress);
+
+                    // The control connection is mid-transfer, so just drop it
+                    if finished.cancelled {
+                        return Ok(());
+                    }
+                }
+
+                client.quit().await;
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                println!(
+                    "[MediaDownloadManager::task::download] Download {} for device: {} failed: {}",
+                    download_id, device.name, e
+                );
+            }
+
+            if finished.cancelled {
+                println!(
+                    "[MediaDownloadManager::task::download] Cancelled download {}",
+                    download_id
+                );
+            }
+
+            let _ = app_handle.emit_all("media-download-finished", &finished);
+            downloads.lock().await.remove(&download_id);
+        });
+
+        println!(
+            "[MediaDownloadManager::start] Started download {}",
+            download_id
+        );
+
+        download_id
+    }
+
+    // The download stops at the next read, removes the partial file and still emits
+    // media-download-finished, with cancelled set
+    pub async fn cancel(&mut self, download_id: u32) -> bool {
+        match self.downloads.lock().await.get(&download_id) {
+            Some(cancel) => {
+                let _ = cancel.send(true);
+                println!(
+                    "[MediaDownloadManager::cancel] Cancelling download {}",
+                    download_id
+                );
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use super::camera::CameraManager;
+use super::config::{get_config_path, Config};
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+const BOUNDARY: &str = "bambuconnectframe";
+const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct MjpegServerConfig {
+    pub enabled: bool,
+    pub bind_address: String,
+    pub port: u16,
+}
+
+impl Default for MjpegServerConfig {
+    fn default() -> Self {
+        MjpegServerConfig {
+            enabled: false,
+            bind_address: "127.0.0.1".to_string(),
+            port: 8090,
+        }
+    }
+}
+
+// Releases the viewer's hold on the upstream camera however the connection ends
+struct ViewerGuard {
+    cameras: &'static CameraManager,
+    dev_id: String,
+}
+
+impl Drop for ViewerGuard {
+    fn drop(&mut self) {
+        self.cameras.release(&self.dev_id);
+    }
+}
+
+// Re-serves printer cameras over plain HTTP so OBS, dashboards etc. can use them:
+//   /                         index of configured devices
+//   /<dev_id>/stream.mjpg     multipart/x-mixed-replace MJPEG stream
+//   /<dev_id>/snapshot.jpg    the latest frame
+pub struct MjpegServer {
+    address: SocketAddr,
+    handle: tokio::task::JoinHandle<()>,
+    shutdown: watch::Sender<bool>, // Ends the open connections when set
+}
+
+impl MjpegServer {
+    pub async fn start(
+        config: &MjpegServerConfig,
+        cameras: &'static CameraManager,
+    ) -> Result<MjpegServer, io::Error> {
+        let listener =
+            TcpListener::bind(format!("{}:{}", config.bind_address, config.port)).await?;
+        let address = listener.local_addr()?;
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+
+        let handle = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        println!(
+                            "[MjpegServer::task::accept] Failed to accept connection: {}",
+                            e
+                        );
+                        continue;
+                    }
+                };
+
+                // Dropping the connection on shutdown also releases its camera subscription
+                let mut shutdown = shutdown_receiver.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        result = handle_connection(stream, cameras) => {
+                            if let Err(e) = result {
+                                println!(
+                                    "[MjpegServer::task::connection] Connection from {} ended: {}",
+                                    peer, e
+                                );
+                            }
+                        }
+                        _ = shutdown.wait_for(|stopped| *stopped) => {}
+                    }
+                });
+            }
+        });
+
+        println!("[MjpegServer::start] Listening on http://{}", address);
+        Ok(MjpegServer {
+            address,
+            handle,
+            shutdown,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stop(self) {
+        self.handle.abort();
+        let _ = self.shutdown.send(true);
+        println!("[MjpegServer::stop] Stopped listening on {}", self.address);
+    }
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), io::Error> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    cameras: &'static CameraManager,
+) -> Result<(), io::Error> {
+    // Only the request line matters, the rest of the head is read and ignored
+    let request_line = {
+        let mut reader = BufReader::new(&mut stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+
+        let mut head_size = request_line.len();
+        loop {
+            let mut line = String::new();
+            let read = reader.read_line(&mut line).await?;
+            head_size += read;
+
+            if read == 0 || line == "\r\n" || line == "\n" {
+                break;
+            }
+
+            if head_size > MAX_REQUEST_HEAD_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Request head too large",
+                ));
+            }
+        }
+
+        request_line
+    };
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("/").split('?').next().unwrap_or("/");
+
+    if method != "GET" {
+        return write_response(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"Only GET is supported",
+        )
+        .await;
+    }
+
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        [] => serve_index(&mut stream).await,
+        [dev_id, "stream.mjpg"] | [dev_id, "stream"] => {
+            serve_stream(&mut stream, cameras, dev_id).await
+        }
+        [dev_id, "snapshot.jpg"] => serve_snapshot(&mut stream, cameras, dev_id).await,
+        _ => write_response(&mut stream, "404 Not Found", "text/plain", b"Not found").await,
+    }
+}
+
+fn load_config() -> Result<Config, io::Error> {
+    Config::load_or_create(&get_config_path()?)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn serve_index(stream: &mut TcpStream) -> Result<(), io::Error> {
+    let config = load_config()?;
+    let mut body = String::from("<!DOCTYPE html><html><head><title>BambuConnect cameras</title></head><body><h1>BambuConnect cameras</h1><ul>");
+
+    for device in config.bambu_devices.iter() {
+        body.push_str(&format!(
+            "<li>{} ({}) - <a href=\"/{}/stream.mjpg\">stream</a> - <a href=\"/{}/snapshot.jpg\">snapshot</a></li>",
+            escape_html(&device.name),
+            escape_html(&device.dev_product_name),
+            escape_html(&device.dev_id),
+            escape_html(&device.dev_id)
+        ));
+    }
+
+    body.push_str("</ul></body></html>");
+    write_response(
+        stream,
+        "200 OK",
+        "text/html; charset=utf-8",
+        body.as_bytes(),
+    )
+    .await
+}
+
+async fn serve_snapshot(
+    stream: &mut TcpStream,
+    cameras: &'static CameraManager,
+    dev_id: &str,
+) -> Result<(), io::Error> {
+    let config = load_config()?;
+    let device = match config.find_device(dev_id) {
+        Some(device) => device,
+        None => {
+            return write_response(stream, "404 Not Found", "text/plain", b"Unknown device").await
+        }
+    };
+
+    match cameras.snapshot(device).await {
+        Ok(jpeg) => write_response(stream, "200 OK", "image/jpeg", &jpeg).await,
+        Err(e) => {
+            write_response(
+                stream,
+                "502 Bad Gateway",
+                "text/plain",
+                e.to_string().as_bytes(),
+            )
+            .await
+        }
+    }
+}
+
+async fn serve_stream(
+    stream: &mut TcpStream,
+    cameras: &'static CameraManager,
+    dev_id: &str,
+) -> Result<(), io::Error> {
+    let config = load_config()?;
+    let device = match config.find_device(dev_id) {
+        Some(device) => device,
+        None => {
+            return write_response(stream, "404 Not Found", "text/plain", b"Unknown device").await
+        }
+    };
+
+    let mut receiver = match cameras.subscribe(device) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            return write_response(
+                stream,
+                "502 Bad Gateway",
+                "text/plain",
+                e.to_string().as_bytes(),
+            )
+            .await
+        }
+    };
+
+    let _guard = ViewerGuard {
+        cameras,
+        dev_id: dev_id.to_string(),
+    };
+
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-store\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        BOUNDARY
+    );
+    stream.write_all(head.as_bytes()).await?;
+
+    println!(
+        "[MjpegServer::serve_stream] Viewer connected to stream for device: {}",
+        device.name
+    );
+
+    // Send whatever frame is already there so viewers don't start on a blank image
+    let mut frame = receiver.borrow_and_update().clone();
+    let mut discard = [0u8; 512];
+    loop {
+        if let Some(frame) = frame.take() {
+            let part_head = format!(
+                "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                frame.jpeg.len()
+            );
+
+            stream.write_all(part_head.as_bytes()).await?;
+            stream.write_all(&frame.jpeg).await?;
+            stream.write_all(b"\r\n").await?;
+            stream.flush().await?;
+        }
+
+        // Watch the socket too, otherwise a viewer leaving while the camera is stalled goes unnoticed
+        tokio::select! {
+            changed = receiver.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+            read = stream.read(&mut discard) => match read {
+                Ok(0) | Err(_) => {
+                    println!(
+                        "[MjpegServer::serve_stream] Viewer disconnected from stream for device: {}",
+                        device.name
+                    );
+                    return Ok(());
+                }
+                // Viewers have nothing more to send, anything extra is ignored
+                Ok(_) => continue,
+            },
+        }
+
+        frame = receiver.borrow_and_update().clone();
+    }
+}
@@ -1,3 +1,17 @@
+pub mod ams;
 pub mod bambu;
+pub mod camera;
 pub mod config;
+pub mod discovery;
+pub mod firmware;
+pub mod ftps;
+pub mod gcode;
+pub mod history;
+pub mod jobs;
+pub mod media;
+pub mod mjpeg;
+pub mod network;
+pub mod snapshots;
 pub mod ssdp;
+pub mod sync;
+pub mod timelapse;
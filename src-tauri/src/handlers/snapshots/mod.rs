@@ -0,0 +1,235 @@
+use super::bambu::BambuDevice;
+use super::camera::CameraManager;
+use super::config::{get_config_dir, get_config_path, Config};
+use super::jobs::{JobEvent, JobRecord};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast;
+
+// Snapshots taken outside of a job (e.g. an HMS error while idle) are kept here
+const IDLE_JOB_DIR: &str = "idle";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotSettings {
+    pub enabled: bool,
+    pub on_start: bool,
+    pub on_finish: bool,
+    pub on_hms: bool,
+    pub progress_interval_percent: u32, // 0 disables progress snapshots
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        SnapshotSettings {
+            enabled: true,
+            on_start: true,
+            on_finish: true,
+            on_hms: true,
+            progress_interval_percent: 25,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JobSnapshot {
+    pub event: String, // start, progress, hms, finish, failed or cancelled
+    pub file: Option<String>,
+    pub captured_at: i64,
+    pub percent: Option<u32>,
+    pub hms_codes: Vec<String>,
+    pub error: Option<String>,
+}
+
+// Written as job.json next to the snapshots so a folder makes sense on its own
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct JobSnapshotLog {
+    pub device_name: String,
+    pub job: Option<JobRecord>,
+    pub snapshots: Vec<JobSnapshot>,
+}
+
+// Where a job's snapshots go, without creating the folder
+pub fn job_snapshot_path(dev_id: &str, job_id: Option<&str>) -> io::Result<PathBuf> {
+    let mut path = get_config_dir()?;
+    path.push("jobs");
+    path.push(dev_id);
+    path.push(job_id.unwrap_or(IDLE_JOB_DIR));
+    Ok(path)
+}
+
+pub fn get_job_snapshot_dir(dev_id: &str, job_id: Option<&str>) -> io::Result<PathBuf> {
+    let path = job_snapshot_path(dev_id, job_id)?;
+    std::fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+impl JobSnapshotLog {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let path = dir.join("job.json");
+        if !path.exists() {
+            return Ok(JobSnapshotLog::default());
+        }
+
+        serde_json::from_str(std::fs::read_to_string(path)?.as_str()).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Could not parse job snapshot log: {}", e),
+            )
+        })
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&self)?;
+        std::fs::write(dir.join("job.json"), json)
+    }
+}
+
+fn load_settings() -> SnapshotSettings {
+    // Reloaded for every event so changes apply without restarting the worker
+    match get_config_path().and_then(|path| Config::load_or_create(&path)) {
+        Ok(config) => config.snapshot_settings,
+        Err(e) => {
+            println!(
+                "[snapshots::load_settings] Failed to load config, using defaults: {}",
+                e
+            );
+            SnapshotSettings::default()
+        }
+    }
+}
+
+async fn take_snapshot(
+    cameras: &'static CameraManager,
+    device: &BambuDevice,
+    job: Option<&JobRecord>,
+    mut snapshot: JobSnapshot,
+) -> io::Result<()> {
+    let dir = get_job_snapshot_dir(&device.dev_id, job.map(|j| j.id.as_str()))?;
+    let mut log = JobSnapshotLog::load(&dir)?;
+
+    match cameras.snapshot(device).await {
+        Ok(jpeg) => {
+            let file = format!("{:03}_{}.jpg", log.snapshots.len() + 1, snapshot.event);
+            tokio::fs::write(dir.join(&file), jpeg).await?;
+            snapshot.file = Some(file);
+        }
+        Err(e) => {
+            // Still record the event, the metadata alone is useful when looking back at a job
+            println!(
+                "[snapshots::take_snapshot] Failed to capture snapshot for device: {}: {}",
+                device.name, e
+            );
+            snapshot.error = Some(e.to_string());
+        }
+    }
+
+    println!(
+        "[snapshots::take_snapshot] Recorded {} snapshot for device: {} in {}",
+        snapshot.event,
+        device.name,
+        dir.display()
+    );
+
+    log.device_name = device.name.clone();
+    if job.is_some() {
+        log.job = job.cloned();
+    }
+    log.snapshots.push(snapshot);
+    log.save(&dir)
+}
+
+// Takes snapshots as job events come in from the MQTT client, until the channel closes
+pub async fn run_snapshot_worker(
+    mut events: broadcast::Receiver<(BambuDevice, JobEvent)>,
+    cameras: &'static CameraManager,
+) {
+    // The last progress step a snapshot was taken at, per job
+    let mut progress_steps: HashMap<String, u32> = HashMap::new();
+
+    loop {
+        let (device, event) = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                println!(
+                    "[snapshots::run_snapshot_worker] Fell behind, skipped {} job events",
+                    skipped
+                );
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let settings = load_settings();
+        if !settings.enabled {
+            continue;
+        }
+
+        let mut snapshot = JobSnapshot {
+            event: String::new(),
+            file: None,
+            captured_at: chrono::Local::now().timestamp(),
+            percent: None,
+            hms_codes: vec![],
+            error: None,
+        };
+
+        let job = match event {
+            JobEvent::Started(job) => {
+                progress_steps.insert(job.id.clone(), 0);
+                if !settings.on_start {
+                    continue;
+                }
+
+                snapshot.event = "start".to_string();
+                Some(job)
+            }
+            // Connected mid-job, progress snapshots carry on from where the job is now
+            JobEvent::Resumed(job, percent) => {
+                if let Some(step) = percent.checked_div(settings.progress_interval_percent) {
+                    progress_steps.insert(job.id, step);
+                }
+                continue;
+            }
+            JobEvent::Progress(job, percent) if settings.progress_interval_percent > 0 => {
+                let step = percent / settings.progress_interval_percent;
+
+                // Jobs that started or resumed while snapshots were off count from here
+                let last_step = progress_steps.entry(job.id.clone()).or_insert(step);
+                if step <= *last_step || percent >= 100 {
+                    continue;
+                }
+
+                *last_step = step;
+                snapshot.event = "progress".to_string();
+                snapshot.percent = Some(percent);
+                Some(job)
+            }
+            JobEvent::Hms(job, codes) if settings.on_hms => {
+                snapshot.event = "hms".to_string();
+                snapshot.hms_codes = codes;
+                job
+            }
+            JobEvent::Finished(job) => {
+                progress_steps.remove(&job.id);
+                if !settings.on_finish {
+                    continue;
+                }
+
+                snapshot.event = job.status.clone();
+                Some(job)
+            }
+            _ => continue,
+        };
+
+        if let Err(e) = take_snapshot(cameras, &device, job.as_ref(), snapshot).await {
+            println!(
+                "[snapshots::run_snapshot_worker] Failed to save snapshot for device: {}: {}",
+                device.name, e
+            );
+        }
+    }
+
+    println!("[snapshots::run_snapshot_worker] Job event channel closed, stopping");
+}
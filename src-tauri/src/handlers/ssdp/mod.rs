@@ -1,5 +1,13 @@
-use std::net::{Ipv4Addr, UdpSocket};
-use std::time::{Duration, Instant};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// The device type Bambu printers announce themselves as
+pub const BAMBU_SEARCH_TARGET: &str = "urn:bambulab-com:device:3dprinter:1";
+
+const SSDP_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
 // Struct to represent an SSDP message
 #[derive(Debug, Clone)]
 pub struct SsdpMessage {
@@ -45,8 +53,12 @@ impl SsdpMessage {
                 continue; // Skip empty lines
             }
 
-            // If the NOTIFY header is found, we need to skip the first line (the NOTIFY line itself)
-            if line.to_lowercase().starts_with("notify") {
+            // Skip the request or status line, NOTIFY for announcements and HTTP/1.1 200 OK for search responses
+            let lower = line.to_lowercase();
+            if lower.starts_with("notify")
+                || lower.starts_with("http/")
+                || lower.starts_with("m-search")
+            {
                 continue;
             }
 
@@ -63,7 +75,7 @@ impl SsdpMessage {
                 }
                 "server" => ssdp_message.server = value.to_string(),
                 "location" => ssdp_message.location = value.to_string(),
-                "nt" => ssdp_message.nt = value.to_string(),
+                "nt" | "st" => ssdp_message.nt = value.to_string(), // Search responses use ST
                 "usn" => ssdp_message.usn = value.to_string(),
                 "cache-control" => ssdp_message.cache_control = value.to_string(),
                 _ => {
@@ -78,65 +90,510 @@ impl SsdpMessage {
     }
 }
 
+// What a Bambu printer says about itself in its SSDP announcements, from the vendor headers
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BambuAnnouncement {
+    pub dev_id: String,
+    pub ip: String,
+    pub model: String, // Model code, e.g. BL-P001 (X1C) or C11 (P1P)
+    pub name: String,
+    pub signal: Option<i32>, // Wi-Fi signal in dBm
+    pub connect: String,     // lan or cloud
+    pub lan_only: bool,
+    pub bind: String, // free or occupied
+    pub bound: bool,
+    pub se_mode: String,
+    pub version: String,
+}
+
+impl SsdpMessage {
+    // Vendor headers like DevName.bambu.com, matched without the case or the .bambu.com suffix
+    pub fn custom_field(&self, name: &str) -> Option<&str> {
+        self.custom_fields
+            .iter()
+            .find(|(header, _)| {
+                let header = header.to_lowercase();
+                let header = header.strip_suffix(".bambu.com").unwrap_or(&header);
+                header == name.to_lowercase()
+            })
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl BambuAnnouncement {
+    // None for anything that isn't a Bambu printer
+    pub fn from_message(message: &SsdpMessage) -> Option<Self> {
+        if message.nt != BAMBU_SEARCH_TARGET || message.usn.is_empty() {
+            return None;
+        }
+
+        let field = |name: &str| message.custom_field(name).unwrap_or_default().to_string();
+
+        // Bambu puts the bare IP in LOCATION, other devices would have a URL there
+        let ip = if message.location.parse::<std::net::IpAddr>().is_ok() {
+            message.location.clone()
+        } else {
+            message.source_address.clone()
+        };
+
+        let connect = field("DevConnect").to_lowercase();
+        let bind = field("DevBind").to_lowercase();
+
+        Some(BambuAnnouncement {
+            dev_id: message.usn.clone(),
+            ip,
+            model: field("DevModel"),
+            name: field("DevName"),
+            signal: message
+                .custom_field("DevSignal")
+                .and_then(|s| s.trim().parse().ok()),
+            lan_only: connect == "lan",
+            connect,
+            bound: bind == "occupied",
+            bind,
+            se_mode: field("DevSEMode"),
+            version: field("DevVersion"),
+        })
+    }
+}
+
 pub struct SsdpListener {
+    ports: Vec<u16>,
+    interfaces: Vec<Ipv4Addr>, // Empty for the OS default
+}
+
+// Interfaces as given, or the unspecified address to let the OS pick one
+fn interfaces_or_default(interfaces: &[Ipv4Addr]) -> Vec<Ipv4Addr> {
+    if interfaces.is_empty() {
+        vec![Ipv4Addr::UNSPECIFIED]
+    } else {
+        interfaces.to_vec()
+    }
+}
+
+// Bind with address reuse so we can listen alongside Bambu Studio or another scan
+fn bind_multicast_socket(
     port: u16,
+    interfaces: &[Ipv4Addr],
+) -> std::io::Result<tokio::net::UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+
+    // Join the SSDP multicast group on every interface, one failing (e.g. a VPN) is fine
+    let mut joined = 0;
+    let mut last_error = None;
+    for interface in interfaces_or_default(interfaces) {
+        match socket.join_multicast_v4(&SSDP_MULTICAST_ADDR, &interface) {
+            Ok(_) => joined += 1,
+            Err(e) => {
+                eprintln!(
+                    "Failed to join SSDP multicast on interface {} for port {}: {}",
+                    interface, port, e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if joined == 0 {
+        return Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Other, "No interface to join")
+        }));
+    }
+    socket.set_nonblocking(true)?;
+
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+// Parse one datagram, logging and skipping anything that isn't a valid SSDP message
+fn parse_datagram(datagram: &[u8], source: SocketAddr) -> Option<SsdpMessage> {
+    let message = match std::str::from_utf8(datagram) {
+        Ok(message) => message,
+        Err(e) => {
+            eprintln!("Skipping non UTF-8 SSDP message from {}: {}", source, e);
+            return None;
+        }
+    };
+
+    match SsdpMessage::from_message(message) {
+        Ok(ssdp_message) => Some(ssdp_message),
+        Err(e) => {
+            eprintln!("Skipping malformed SSDP message from {}: {}", source, e);
+            None
+        }
+    }
+}
+
+// Search responses have no HOST header, the sender is the device
+fn parse_search_response(datagram: &[u8], source: SocketAddr) -> Option<SsdpMessage> {
+    let mut ssdp_message = parse_datagram(datagram, source)?;
+    ssdp_message.source_address = source.ip().to_string();
+    ssdp_message.source_port = source.port();
+
+    // Only Bambu's own responses carry the IP in LOCATION, fall back to the sender
+    if ssdp_message.location.is_empty() {
+        ssdp_message.location = source.ip().to_string();
+    }
+
+    Some(ssdp_message)
 }
 
 impl SsdpListener {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    pub fn new(ports: Vec<u16>, interfaces: Vec<Ipv4Addr>) -> Self {
+        Self { ports, interfaces }
     }
 
-    pub async fn listen(
-        &self,
-        duration: Duration,
-    ) -> Result<Vec<SsdpMessage>, Box<dyn std::error::Error>> {
-        // Create a UDP socket bound to the specified port
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", self.port))?;
-        socket.set_read_timeout(Some(duration)).map_err(|e| {
-            eprintln!("Error setting read timeout: {}", e);
-            e
-        })?;
-
-        // Join the SSDP multicast group
-        socket.join_multicast_v4(
-            &Ipv4Addr::new(239, 255, 255, 250),
-            &Ipv4Addr::new(0, 0, 0, 0),
-        )?;
+    // Collect NOTIFY messages on every port at once for the whole duration. Ports that can't
+    // be bound are skipped, it's only an error when none can
+    pub async fn listen(&self, duration: Duration) -> std::io::Result<Vec<SsdpMessage>> {
+        let deadline = tokio::time::Instant::now() + duration;
+        let sockets = self.bind_all()?;
 
-        // Buffer to store the received message
-        let mut buf = [0u8; 2048];
+        println!(
+            "Listening for SSDP NOTIFY messages on ports {:?} for {:?}...",
+            self.ports, duration
+        );
 
-        let start_time = Instant::now();
-        let mut messages = Vec::new();
+        let receivers = sockets
+            .into_iter()
+            .map(|(port, socket)| Self::receive_until(port, socket, deadline));
+        let messages = futures::future::join_all(receivers).await;
+
+        Ok(messages.into_iter().flatten().collect())
+    }
+
+    // Pass every message on as it arrives, until the receiving end is dropped
+    pub async fn listen_forever(
+        &self,
+        messages: mpsc::UnboundedSender<SsdpMessage>,
+    ) -> std::io::Result<()> {
+        let sockets = self.bind_all()?;
 
         println!(
-            "Listening for SSDP NOTIFY messages on port {} for {:?}...",
-            self.port, duration
+            "Listening for SSDP NOTIFY messages on ports {:?} until stopped...",
+            self.ports
         );
 
-        // Receive messages until the specified duration elapses
-        while Instant::now() - start_time < duration {
-            match socket.recv_from(&mut buf) {
-                Ok((size, _)) => {
-                    println!("Received message of size {}", size);
-                    let message = std::str::from_utf8(&buf[..size])?;
-                    let ssdp_message = SsdpMessage::from_message(message)?;
-                    messages.push(ssdp_message);
+        let forwarders = sockets
+            .into_iter()
+            .map(|(port, socket)| Self::forward(port, socket, messages.clone()));
+        futures::future::join_all(forwarders).await;
+
+        Ok(())
+    }
+
+    fn bind_all(&self) -> std::io::Result<Vec<(u16, tokio::net::UdpSocket)>> {
+        let mut sockets = vec![];
+        let mut bind_errors = vec![];
+        for port in self.ports.iter() {
+            match bind_multicast_socket(*port, &self.interfaces) {
+                Ok(socket) => sockets.push((*port, socket)),
+                Err(e) => {
+                    eprintln!("Failed to listen for SSDP messages on port {}: {}", port, e);
+                    bind_errors.push(format!("port {}: {}", port, e));
+                }
+            }
+        }
+
+        if sockets.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!(
+                    "Could not listen for SSDP messages on any port ({})",
+                    bind_errors.join(", ")
+                ),
+            ));
+        }
+
+        Ok(sockets)
+    }
+
+    async fn forward(
+        port: u16,
+        socket: tokio::net::UdpSocket,
+        messages: mpsc::UnboundedSender<SsdpMessage>,
+    ) {
+        let mut buf = [0u8; 2048];
+
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((size, source)) => {
+                    if let Some(message) = parse_datagram(&buf[..size], source) {
+                        if messages.send(message).is_err() {
+                            break;
+                        }
+                    }
                 }
                 Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock
-                        || e.kind() == std::io::ErrorKind::TimedOut
-                    {
-                        println!("No more messages received within the specified duration");
-                        break;
-                    } else {
-                        eprintln!("Error receiving message: {}", e);
+                    eprintln!("Error receiving message on port {}: {}", port, e);
+
+                    // Don't spin if the socket is broken for good
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    async fn receive_until(
+        port: u16,
+        socket: tokio::net::UdpSocket,
+        deadline: tokio::time::Instant,
+    ) -> Vec<SsdpMessage> {
+        // Buffer to store the received message
+        let mut buf = [0u8; 2048];
+        let mut messages = Vec::new();
+
+        loop {
+            match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                Ok(Ok((size, source))) => {
+                    if let Some(message) = parse_datagram(&buf[..size], source) {
+                        messages.push(message);
                     }
                 }
+                Ok(Err(e)) => eprintln!("Error receiving message on port {}: {}", port, e),
+                Err(_) => break,
+            }
+        }
+
+        println!("Received {} SSDP messages on port {}", messages.len(), port);
+        messages
+    }
+}
+
+// Actively asks devices to announce themselves, for printers that rarely send NOTIFY
+pub struct SsdpSearcher {
+    ports: Vec<u16>,
+    interfaces: Vec<Ipv4Addr>, // Empty for the OS default
+}
+
+impl SsdpSearcher {
+    pub fn new(ports: Vec<u16>, interfaces: Vec<Ipv4Addr>) -> Self {
+        Self { ports, interfaces }
+    }
+
+    pub async fn search(
+        &self,
+        search_target: &str,
+        duration: Duration,
+    ) -> std::io::Result<Vec<SsdpMessage>> {
+        // Responses are unicast back to whatever port we send from
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+        socket.set_multicast_ttl_v4(2)?;
+
+        // Devices should answer within MX seconds, leave the rest of the window for stragglers
+        let mx = duration.as_secs().clamp(1, 5);
+
+        // The request goes out of one interface at a time
+        for interface in interfaces_or_default(&self.interfaces) {
+            if let Err(e) = SockRef::from(&socket).set_multicast_if_v4(&interface) {
+                eprintln!(
+                    "Failed to send M-SEARCH from interface {}: {}",
+                    interface, e
+                );
+                continue;
+            }
+
+            for port in self.ports.iter() {
+                let request = format!(
+                    "M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {}\r\n\r\n",
+                    SSDP_MULTICAST_ADDR, port, mx, search_target
+                );
+
+                if let Err(e) = socket
+                    .send_to(request.as_bytes(), (SSDP_MULTICAST_ADDR, *port))
+                    .await
+                {
+                    println!(
+                        "Failed to send M-SEARCH to port {} from interface {}: {}",
+                        port, interface, e
+                    );
+                }
+            }
+        }
+
+        println!(
+            "Sent M-SEARCH for {} on ports {:?}, collecting responses for {:?}...",
+            search_target, self.ports, duration
+        );
+
+        let mut buf = [0u8; 2048];
+        let mut messages = Vec::new();
+        let deadline = tokio::time::Instant::now() + duration;
+
+        loop {
+            let (size, source) =
+                match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                    Ok(Ok(received)) => received,
+                    Ok(Err(e)) => {
+                        eprintln!("Error receiving M-SEARCH response: {}", e);
+                        continue;
+                    }
+                    Err(_) => break,
+                };
+
+            if let Some(ssdp_message) = parse_search_response(&buf[..size], source) {
+                messages.push(ssdp_message);
             }
         }
 
         Ok(messages)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTIFY: &str = "NOTIFY * HTTP/1.1\r\n\
+        HOST: 239.255.255.250:1990\r\n\
+        Server: UPnP/1.0\r\n\
+        Location: 192.168.1.50\r\n\
+        NT: urn:bambulab-com:device:3dprinter:1\r\n\
+        USN: 01P00A000000001\r\n\
+        Cache-Control: max-age=1800\r\n\
+        DevModel.bambu.com: C12\r\n\
+        DevName.bambu.com: Workshop P1S\r\n\
+        DevSignal.bambu.com: -52\r\n\
+        DevConnect.bambu.com: LAN\r\n\
+        DevBind.bambu.com: occupied\r\n\
+        Devseclink.bambu.com: secure\r\n\
+        DevVersion.bambu.com: 01.07.00.00\r\n\
+        DevSEMode.bambu.com: 1\r\n\r\n";
+
+    #[test]
+    fn parses_notify_headers() {
+        let message = SsdpMessage::from_message(NOTIFY).unwrap();
+
+        assert_eq!(message.source_address, "239.255.255.250");
+        assert_eq!(message.source_port, 1990);
+        assert_eq!(message.server, "UPnP/1.0");
+        assert_eq!(message.location, "192.168.1.50");
+        assert_eq!(message.nt, BAMBU_SEARCH_TARGET);
+        assert_eq!(message.usn, "01P00A000000001");
+        assert_eq!(message.cache_control, "max-age=1800");
+        assert_eq!(message.custom_field("devname"), Some("Workshop P1S"));
+        assert_eq!(message.custom_field("DevSecLink"), Some("secure"));
+        assert_eq!(message.custom_field("DevMissing"), None);
+    }
+
+    #[test]
+    fn reads_a_bambu_announcement() {
+        let message = SsdpMessage::from_message(NOTIFY).unwrap();
+        let announcement = BambuAnnouncement::from_message(&message).unwrap();
+
+        assert_eq!(announcement.dev_id, "01P00A000000001");
+        assert_eq!(announcement.ip, "192.168.1.50");
+        assert_eq!(announcement.model, "C12");
+        assert_eq!(announcement.name, "Workshop P1S");
+        assert_eq!(announcement.signal, Some(-52));
+        assert_eq!(announcement.connect, "lan");
+        assert!(announcement.lan_only);
+        assert_eq!(announcement.bind, "occupied");
+        assert!(announcement.bound);
+        assert_eq!(announcement.se_mode, "1");
+        assert_eq!(announcement.version, "01.07.00.00");
+    }
+
+    #[test]
+    fn missing_vendor_headers_are_left_empty() {
+        let message = SsdpMessage::from_message(
+            "NOTIFY * HTTP/1.1\r\n\
+            HOST: 192.168.1.51:2021\r\n\
+            Location: http://192.168.1.51/desc.xml\r\n\
+            NT: urn:bambulab-com:device:3dprinter:1\r\n\
+            USN: 00M09A000000002\r\n\
+            DevSignal.bambu.com: strong\r\n\r\n",
+        )
+        .unwrap();
+        let announcement = BambuAnnouncement::from_message(&message).unwrap();
+
+        // Not a bare IP in LOCATION, so the sender's address is used
+        assert_eq!(announcement.ip, "192.168.1.51");
+        assert_eq!(announcement.name, "");
+        assert_eq!(announcement.signal, None);
+        assert!(!announcement.lan_only);
+        assert!(!announcement.bound);
+    }
+
+    #[test]
+    fn other_devices_are_not_announcements() {
+        let router = SsdpMessage::from_message(
+            "NOTIFY * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1900\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\
+            NT: upnp:rootdevice\r\n\
+            USN: uuid:router::upnp:rootdevice\r\n\r\n",
+        )
+        .unwrap();
+        assert!(BambuAnnouncement::from_message(&router).is_none());
+
+        let without_usn = SsdpMessage::from_message(
+            "NOTIFY * HTTP/1.1\r\nNT: urn:bambulab-com:device:3dprinter:1\r\n\r\n",
+        )
+        .unwrap();
+        assert!(BambuAnnouncement::from_message(&without_usn).is_none());
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        assert!(SsdpMessage::from_message("NOTIFY * HTTP/1.1\r\nnot a header\r\n").is_err());
+        assert!(SsdpMessage::from_message("HOST: 239.255.255.250:port\r\n").is_err());
+    }
+
+    #[test]
+    fn reads_search_responses_from_the_sender() {
+        let source: SocketAddr = "192.168.1.52:2021".parse().unwrap();
+        let response = "HTTP/1.1 200 OK\r\n\
+            Cache-Control: max-age=1800\r\n\
+            ST: urn:bambulab-com:device:3dprinter:1\r\n\
+            USN: 03W00X000000003\r\n\
+            DevName.bambu.com: Garage X1C\r\n\r\n";
+
+        let message = parse_search_response(response.as_bytes(), source).unwrap();
+        assert_eq!(message.source_address, "192.168.1.52");
+        assert_eq!(message.source_port, 2021);
+        assert_eq!(message.location, "192.168.1.52");
+        assert_eq!(message.nt, BAMBU_SEARCH_TARGET);
+
+        let announcement = BambuAnnouncement::from_message(&message).unwrap();
+        assert_eq!(announcement.ip, "192.168.1.52");
+        assert_eq!(announcement.name, "Garage X1C");
+    }
+
+    #[test]
+    fn search_responses_keep_the_ip_bambu_reports() {
+        let source: SocketAddr = "10.0.0.2:1990".parse().unwrap();
+        let response = "HTTP/1.1 200 OK\r\n\
+            Location: 192.168.1.53\r\n\
+            ST: urn:bambulab-com:device:3dprinter:1\r\n\
+            USN: 01S00C000000004\r\n\r\n";
+
+        let message = parse_search_response(response.as_bytes(), source).unwrap();
+        assert_eq!(message.source_address, "10.0.0.2");
+        assert_eq!(message.location, "192.168.1.53");
+        assert_eq!(
+            BambuAnnouncement::from_message(&message).unwrap().ip,
+            "192.168.1.53"
+        );
+    }
+
+    #[test]
+    fn search_requests_and_garbage_are_skipped() {
+        let source: SocketAddr = "192.168.1.60:1900".parse().unwrap();
+
+        // Our own M-SEARCH looped back is parsed, but isn't a printer
+        let request = "M-SEARCH * HTTP/1.1\r\n\
+            HOST: 239.255.255.250:1990\r\n\
+            MAN: \"ssdp:discover\"\r\n\
+            MX: 5\r\n\
+            ST: urn:bambulab-com:device:3dprinter:1\r\n\r\n";
+        let message = parse_search_response(request.as_bytes(), source).unwrap();
+        assert!(message.usn.is_empty());
+        assert!(BambuAnnouncement::from_message(&message).is_none());
+
+        assert!(parse_search_response(&[0xff, 0xfe, 0x00], source).is_none());
+    }
+}
@@ -0,0 +1,221 @@
+use super::bambu::{BambuApiError, BambuClient, BambuDevice};
+use super::config::{get_config_path, update_config, Config};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tauri::Manager;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeviceSyncSettings {
+    pub enabled: bool,
+    pub interval_minutes: u64,
+}
+
+impl Default for DeviceSyncSettings {
+    fn default() -> Self {
+        DeviceSyncSettings {
+            enabled: true,
+            interval_minutes: 30,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DeviceChange {
+    pub dev_id: String,
+    pub name: String,
+    pub kind: String, // added, renamed, access_code_changed, model_changed, removed or restored
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct DevicesChangedEvent {
+    pub changes: Vec<DeviceChange>,
+    pub devices: Vec<BambuDevice>,
+}
+
+fn change(
+    device: &BambuDevice,
+    kind: &str,
+    old_value: Option<&str>,
+    new_value: Option<&str>,
+) -> DeviceChange {
+    DeviceChange {
+        dev_id: device.dev_id.clone(),
+        name: device.name.clone(),
+        kind: kind.to_string(),
+        old_value: old_value.map(|v| v.to_string()),
+        new_value: new_value.map(|v| v.to_string()),
+    }
+}
+
+// Bring the configured devices in line with what the cloud reports. IP addresses only come
+// from discovery, so they are kept, and removed devices are flagged rather than deleted
+pub fn apply_cloud_devices(
+    config: &mut Config,
+    cloud_devices: &[BambuDevice],
+) -> Vec<DeviceChange> {
+    let mut changes = vec![];
+
+    for device in config.bambu_devices.iter_mut() {
+        let cloud_device = match cloud_devices.iter().find(|d| d.dev_id == device.dev_id) {
+            Some(cloud_device) => cloud_device,
+            None => {
+                if !device.removed {
+                    device.removed = true;
+                    changes.push(change(device, "removed", None, None));
+                }
+                continue;
+            }
+        };
+
+        if device.removed {
+            device.removed = false;
+            changes.push(change(device, "restored", None, None));
+        }
+
+        if device.name != cloud_device.name {
+            changes.push(change(
+                device,
+                "renamed",
+                Some(&device.name),
+                Some(&cloud_device.name),
+            ));
+            device.name = cloud_device.name.clone();
+        }
+
+        if device.dev_access_code != cloud_device.dev_access_code {
+            // Don't put the codes themselves in events, they end up in logs
+            changes.push(change(device, "access_code_changed", None, None));
+            device.dev_access_code = cloud_device.dev_access_code.clone();
+        }
+
+        if device.dev_product_name != cloud_device.dev_product_name {
+            changes.push(change(
+                device,
+                "model_changed",
+                Some(&device.dev_product_name),
+                Some(&cloud_device.dev_product_name),
+            ));
+            device.dev_product_name = cloud_device.dev_product_name.clone();
+            device.dev_model_name = cloud_device.dev_model_name.clone();
+        }
+
+        device.online = cloud_device.online;
+        device.print_status = cloud_device.print_status.clone();
+        device.nozzle_diameter = cloud_device.nozzle_diameter.clone();
+    }
+
+    for cloud_device in cloud_devices {
+        if !config
+            .bambu_devices
+            .iter()
+            .any(|d| d.dev_id == cloud_device.dev_id)
+        {
+            changes.push(change(cloud_device, "added", None, None));
+            config.bambu_devices.push(cloud_device.clone());
+        }
+    }
+
+    changes
+}
+
+// Fetch the device list once, save and announce any differences
+pub async fn sync_devices(
+    app_handle: &tauri::AppHandle,
+    client: &BambuClient,
+) -> Result<Vec<DeviceChange>, BambuApiError> {
+    let response = client.get_devices(None).await?;
+
+    let (changes, devices) = update_config(|config| {
+        let changes = apply_cloud_devices(config, &response.devices);
+        Ok((changes, config.bambu_devices.clone()))
+    })?;
+
+    if changes.is_empty() {
+        return Ok(changes);
+    }
+
+    println!(
+        "[sync::sync_devices] {} device changes from the cloud: {:?}",
+        changes.len(),
+        changes
+    );
+
+    let _ = app_handle.emit_all(
+        "devices-changed",
+        DevicesChangedEvent {
+            changes: changes.clone(),
+            devices,
+        },
+    );
+
+    Ok(changes)
+}
+
+// Drop a device that was deliberately unbound, rather than waiting for a sync to flag it
+pub fn forget_device(
+    app_handle: &tauri::AppHandle,
+    dev_id: &str,
+) -> Result<Vec<DeviceChange>, BambuApiError> {
+    let removed = update_config(|config| {
+        let index = match config.bambu_devices.iter().position(|d| d.dev_id == dev_id) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let device = config.bambu_devices.remove(index);
+        Ok(Some((device, config.bambu_devices.clone())))
+    })?;
+
+    let (device, devices) = match removed {
+        Some(removed) => removed,
+        None => return Ok(vec![]),
+    };
+    let changes = vec![change(&device, "removed", None, None)];
+
+    let _ = app_handle.emit_all(
+        "devices-changed",
+        DevicesChangedEvent {
+            changes: changes.clone(),
+            devices,
+        },
+    );
+
+    Ok(changes)
+}
+
+fn load_settings() -> DeviceSyncSettings {
+    match get_config_path().and_then(|path| Config::load_or_create(&path)) {
+        Ok(config) => config.device_sync,
+        Err(e) => {
+            println!(
+                "[sync::load_settings] Failed to load config, using defaults: {}",
+                e
+            );
+            DeviceSyncSettings::default()
+        }
+    }
+}
+
+// Keeps the configured devices in sync with the account until the session expires
+pub async fn run_device_sync(app_handle: tauri::AppHandle, client: &'static BambuClient) {
+    loop {
+        let settings = load_settings();
+
+        if settings.enabled {
+            if let Err(e) = sync_devices(&app_handle, client).await {
+                println!("[sync::run_device_sync] Failed to sync devices: {}", e);
+
+                // The session refresher tells the frontend, nothing more to do here until sign in
+                if e.is_unauthorized() {
+                    break;
+                }
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(settings.interval_minutes.max(1) * 60)).await;
+    }
+
+    println!("[sync::run_device_sync] Stopped syncing devices");
+}
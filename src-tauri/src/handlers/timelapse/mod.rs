@@ -0,0 +1,399 @@
+use super::bambu::BambuDevice;
+use super::camera::CameraManager;
+use super::config::{get_config_path, Config};
+use super::jobs::{JobEvent, JobRecord};
+use super::snapshots::job_snapshot_path;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast;
+
+const FRAMES_DIR: &str = "timelapse_frames";
+const OUTPUT_FILE: &str = "timelapse.avi";
+
+// AVI flags, see the OpenDML / Microsoft AVI RIFF reference
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimelapseSettings {
+    pub enabled: bool,
+    pub fps: u32,
+    pub keep_frames: bool,
+}
+
+impl Default for TimelapseSettings {
+    fn default() -> Self {
+        // Off by default, printers with working timelapses already record their own
+        TimelapseSettings {
+            enabled: false,
+            fps: 24,
+            keep_frames: false,
+        }
+    }
+}
+
+fn timelapse_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+// Read the width and height from a JPEG's start of frame marker
+pub fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u16, u16)> {
+    let mut i = 2;
+
+    while i + 9 < jpeg.len() {
+        if jpeg[i] != 0xFF {
+            return None;
+        }
+
+        let marker = jpeg[i + 1];
+        let length = u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+
+        // SOF0 to SOF15, except DHT (C4), JPG (C8) and DAC (CC) which share the range
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let height = u16::from_be_bytes([jpeg[i + 5], jpeg[i + 6]]);
+            let width = u16::from_be_bytes([jpeg[i + 7], jpeg[i + 8]]);
+            return Some((width, height));
+        }
+
+        i += 2 + length;
+    }
+
+    None
+}
+
+fn write_chunk_header<W: Write>(writer: &mut W, id: &[u8; 4], size: u32) -> io::Result<()> {
+    writer.write_all(id)?;
+    writer.write_all(&size.to_le_bytes())
+}
+
+fn write_u32s<W: Write>(writer: &mut W, values: &[u32]) -> io::Result<()> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Write JPEG frames into a Motion JPEG AVI. Frames are streamed from disk one at a time,
+// so their sizes are taken from the file system up front to fill in the headers
+pub fn write_mjpeg_avi(frames: &[PathBuf], fps: u32, output: &Path) -> io::Result<()> {
+    let first_frame = match frames.first() {
+        Some(frame) => std::fs::read(frame)?,
+        None => return Err(timelapse_error("No frames to write".to_string())),
+    };
+
+    let (width, height) = jpeg_dimensions(&first_frame).ok_or_else(|| {
+        timelapse_error(format!(
+            "Could not read the dimensions of {}",
+            frames[0].display()
+        ))
+    })?;
+    let (width, height) = (width as u32, height as u32);
+
+    let mut sizes = Vec::with_capacity(frames.len());
+    for frame in frames {
+        sizes.push(std::fs::metadata(frame)?.len() as u32);
+    }
+
+    let fps = fps.max(1);
+    let frame_count = frames.len() as u32;
+    let max_frame_size = sizes.iter().copied().max().unwrap_or(0);
+
+    // Chunks are padded to an even size
+    let padded = |size: u32| size + (size & 1);
+    let movi_size = 4 + sizes.iter().map(|s| 8 + padded(*s)).sum::<u32>();
+    let strl_size = 4 + (8 + 56) + (8 + 40);
+    let hdrl_size = 4 + (8 + 56) + (8 + strl_size);
+    let idx1_size = 16 * frame_count;
+    let riff_size = 4 + (8 + hdrl_size) + (8 + movi_size) + (8 + idx1_size);
+
+    let mut writer = BufWriter::new(File::create(output)?);
+
+    write_chunk_header(&mut writer, b"RIFF", riff_size)?;
+    writer.write_all(b"AVI ")?;
+
+    write_chunk_header(&mut writer, b"LIST", hdrl_size)?;
+    writer.write_all(b"hdrl")?;
+
+    write_chunk_header(&mut writer, b"avih", 56)?;
+    write_u32s(
+        &mut writer,
+        &[
+            1_000_000 / fps,
+            max_frame_size * fps,
+            0,
+            AVIF_HASINDEX,
+            frame_count,
+            0,
+            1,
+            max_frame_size,
+            width,
+            height,
+            0,
+            0,
+            0,
+            0,
+        ],
+    )?;
+
+    write_chunk_header(&mut writer, b"LIST", strl_size)?;
+    writer.write_all(b"strl")?;
+
+    write_chunk_header(&mut writer, b"strh", 56)?;
+    writer.write_all(b"vids")?;
+    writer.write_all(b"MJPG")?;
+    write_u32s(
+        &mut writer,
+        &[0, 0, 0, 1, fps, 0, frame_count, max_frame_size],
+    )?;
+    write_u32s(&mut writer, &[u32::MAX, 0])?; // quality (default), sample size (varies)
+    for value in [0u16, 0, width as u16, height as u16] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+
+    write_chunk_header(&mut writer, b"strf", 40)?;
+    write_u32s(&mut writer, &[40, width, height])?;
+    writer.write_all(&1u16.to_le_bytes())?; // planes
+    writer.write_all(&24u16.to_le_bytes())?; // bits per pixel
+    writer.write_all(b"MJPG")?;
+    write_u32s(&mut writer, &[width * height * 3, 0, 0, 0, 0])?;
+
+    write_chunk_header(&mut writer, b"LIST", movi_size)?;
+    writer.write_all(b"movi")?;
+
+    for (frame, size) in frames.iter().zip(sizes.iter()) {
+        let jpeg = std::fs::read(frame)?;
+        if jpeg.len() as u32 != *size {
+            return Err(timelapse_error(format!(
+                "{} changed while the timelapse was being written",
+                frame.display()
+            )));
+        }
+
+        write_chunk_header(&mut writer, b"00dc", *size)?;
+        writer.write_all(&jpeg)?;
+        if size & 1 == 1 {
+            writer.write_all(&[0])?;
+        }
+    }
+
+    // Index offsets are relative to the "movi" fourcc
+    write_chunk_header(&mut writer, b"idx1", idx1_size)?;
+    let mut offset = 4;
+    for size in sizes {
+        writer.write_all(b"00dc")?;
+        write_u32s(&mut writer, &[AVIIF_KEYFRAME, offset, size])?;
+        offset += 8 + padded(size);
+    }
+
+    writer.flush()
+}
+
+fn load_settings() -> TimelapseSettings {
+    match get_config_path().and_then(|path| Config::load_or_create(&path)) {
+        Ok(config) => config.timelapse_settings,
+        Err(e) => {
+            println!(
+                "[timelapse::load_settings] Failed to load config, using defaults: {}",
+                e
+            );
+            TimelapseSettings::default()
+        }
+    }
+}
+
+// Only the path, the folder is created with the first frame
+fn get_frames_dir(job: &JobRecord) -> io::Result<PathBuf> {
+    let mut path = job_snapshot_path(&job.dev_id, Some(&job.id))?;
+    path.push(FRAMES_DIR);
+    Ok(path)
+}
+
+async fn capture_layer_frame(
+    cameras: &'static CameraManager,
+    device: &BambuDevice,
+    job: &JobRecord,
+    layer_num: u32,
+) -> io::Result<()> {
+    let dir = get_frames_dir(job)?;
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let jpeg = cameras.snapshot(device).await?;
+    tokio::fs::write(dir.join(format!("layer_{:05}.jpg", layer_num)), jpeg).await
+}
+
+// Turn the frames captured for a job into timelapse.avi next to them
+pub fn assemble_timelapse(job: &JobRecord, fps: u32, keep_frames: bool) -> io::Result<PathBuf> {
+    let dir = get_frames_dir(job)?;
+    if !dir.exists() {
+        return Err(timelapse_error(format!(
+            "No timelapse frames were captured for job: {}",
+            job.id
+        )));
+    }
+
+    // Frame names are zero padded layer numbers, so sorting by name keeps them in order
+    let mut frames: Vec<PathBuf> = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map(|e| e == "jpg").unwrap_or(false))
+        .collect();
+    frames.sort();
+
+    let output = dir
+        .parent()
+        .map(|p| p.join(OUTPUT_FILE))
+        .unwrap_or_else(|| dir.join(OUTPUT_FILE));
+    write_mjpeg_avi(&frames, fps, &output)?;
+
+    if !keep_frames {
+        std::fs::remove_dir_all(&dir)?;
+    }
+
+    println!(
+        "[timelapse::assemble_timelapse] Wrote {} frames for job: {} to {}",
+        frames.len(),
+        job.id,
+        output.display()
+    );
+
+    Ok(output)
+}
+
+// Grabs a frame at every layer change and builds the timelapse once the job ends
+pub async fn run_timelapse_worker(
+    mut events: broadcast::Receiver<(BambuDevice, JobEvent)>,
+    cameras: &'static CameraManager,
+) {
+    loop {
+        let (device, event) = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                println!(
+                    "[timelapse::run_timelapse_worker] Fell behind, skipped {} job events",
+                    skipped
+                );
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        match event {
+            JobEvent::LayerChanged(job, layer_num) => {
+                if !load_settings().enabled {
+                    continue;
+                }
+
+                if let Err(e) = capture_layer_frame(cameras, &device, &job, layer_num).await {
+                    println!(
+                        "[timelapse::run_timelapse_worker] Failed to capture layer {} for device: {}: {}",
+                        layer_num, device.name, e
+                    );
+                }
+            }
+            JobEvent::Finished(job) => {
+                // Assemble whatever was captured, even if timelapses were turned off mid-job
+                let has_frames = get_frames_dir(&job).map(|d| d.exists()).unwrap_or(false);
+                if !has_frames {
+                    continue;
+                }
+
+                // Writing the video can take a while, don't hold up other devices' layer changes
+                let settings = load_settings();
+                tokio::spawn(async move {
+                    let result = tokio::task::spawn_blocking(move || {
+                        assemble_timelapse(&job, settings.fps, settings.keep_frames)
+                    })
+                    .await;
+
+                    match result {
+                        Ok(Ok(_)) => {}
+                        Ok(Err(e)) => println!(
+                            "[timelapse::run_timelapse_worker] Failed to assemble timelapse for device: {}: {}",
+                            device.name, e
+                        ),
+                        Err(e) => println!(
+                            "[timelapse::run_timelapse_worker] Timelapse task for device: {} failed: {}",
+                            device.name, e
+                        ),
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+
+    println!("[timelapse::run_timelapse_worker] Job event channel closed, stopping");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Just enough of a baseline JPEG for the dimensions to be read: SOI, an APP0 segment and SOF0
+    fn fake_jpeg(width: u16, height: u16, padding: usize) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00];
+        jpeg.extend([0xFF, 0xC0, 0x00, 0x11, 0x08]);
+        jpeg.extend(height.to_be_bytes());
+        jpeg.extend(width.to_be_bytes());
+        jpeg.extend(vec![0u8; padding]);
+        jpeg.extend([0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn reads_jpeg_dimensions_after_other_segments() {
+        assert_eq!(
+            jpeg_dimensions(&fake_jpeg(1280, 720, 12)),
+            Some((1280, 720))
+        );
+        assert_eq!(jpeg_dimensions(&[0xFF, 0xD8, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn writes_a_consistent_avi() {
+        let dir = std::env::temp_dir().join(format!("timelapse-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // An odd sized frame checks the chunk padding
+        let frames: Vec<PathBuf> = [12, 13]
+            .iter()
+            .enumerate()
+            .map(|(i, padding)| {
+                let path = dir.join(format!("layer_{:05}.jpg", i + 1));
+                std::fs::write(&path, fake_jpeg(640, 480, *padding)).unwrap();
+                path
+            })
+            .collect();
+
+        let output = dir.join(OUTPUT_FILE);
+        write_mjpeg_avi(&frames, 30, &output).unwrap();
+        let avi = std::fs::read(&output).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(&avi[0..4], b"RIFF");
+        assert_eq!(read_u32(&avi, 4) as usize, avi.len() - 8);
+        assert_eq!(&avi[8..12], b"AVI ");
+
+        // avih: microseconds per frame, frame count, width and height
+        assert_eq!(&avi[24..28], b"avih");
+        assert_eq!(read_u32(&avi, 32), 1_000_000 / 30);
+        assert_eq!(read_u32(&avi, 48), 2);
+        assert_eq!((read_u32(&avi, 64), read_u32(&avi, 68)), (640, 480));
+
+        // The index is last, one 16 byte entry per frame
+        let idx1 = avi.len() - 8 - 32;
+        assert_eq!(&avi[idx1..idx1 + 4], b"idx1");
+        assert_eq!(read_u32(&avi, idx1 + 4), 32);
+    }
+
+    #[test]
+    fn refuses_to_write_without_frames() {
+        let output = std::env::temp_dir().join("timelapse-test-empty.avi");
+        assert!(write_mjpeg_avi(&[], 24, &output).is_err());
+    }
+}
@@ -5,10 +5,19 @@ mod commands;
 mod constants;
 mod handlers;
 use commands::bambu::{
-    deinit_mqtt_worker, discover_devices, fetch_devices, get_jwt, init_mqtt_worker, login_to_bambu,
-    set_jwt, unwatch_device, watch_device,
+    bind_device, cancel_cloud_request, deinit_mqtt_worker, discover_devices, fetch_devices,
+    fetch_task_history, get_account_profile, get_cloud_endpoints, get_discovered_devices,
+    get_firmware_report, get_jwt, init_mqtt_worker, list_network_interfaces, login_to_bambu,
+    refresh_session, rename_device, request_login_code, set_jwt, submit_login_code,
+    suggest_ams_mapping, sync_devices, unbind_device, unwatch_device, watch_device,
+};
+use commands::camera::{
+    capture_snapshot, get_mjpeg_server_address, handle_camera_protocol, start_camera,
+    start_mjpeg_server, start_mjpeg_server_if_enabled, stop_camera, stop_mjpeg_server,
 };
 use commands::config::{get_config, init_config, save_config};
+use commands::gcode::{analyze_gcode, analyze_printer_gcode};
+use commands::media::{cancel_media_download, download_printer_media, list_printer_media};
 use commands::util::quit;
 
 #[tokio::main]
@@ -20,15 +29,46 @@ async fn main() {
             save_config,
             quit,
             login_to_bambu,
+            request_login_code,
+            submit_login_code,
             set_jwt,
             get_jwt,
+            get_cloud_endpoints,
+            get_account_profile,
+            get_firmware_report,
+            refresh_session,
+            cancel_cloud_request,
             fetch_devices,
+            sync_devices,
+            bind_device,
+            unbind_device,
+            rename_device,
+            fetch_task_history,
             discover_devices,
+            get_discovered_devices,
+            list_network_interfaces,
             init_mqtt_worker,
             deinit_mqtt_worker,
             watch_device,
-            unwatch_device
+            unwatch_device,
+            suggest_ams_mapping,
+            analyze_gcode,
+            analyze_printer_gcode,
+            list_printer_media,
+            download_printer_media,
+            cancel_media_download,
+            start_camera,
+            stop_camera,
+            capture_snapshot,
+            start_mjpeg_server,
+            stop_mjpeg_server,
+            get_mjpeg_server_address
         ])
+        .register_uri_scheme_protocol("camera", handle_camera_protocol)
+        .setup(|_app| {
+            tauri::async_runtime::spawn(start_mjpeg_server_if_enabled());
+            Ok(())
+        })
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
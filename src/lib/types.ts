@@ -2,6 +2,93 @@ export type Config = {
 	is_first_run: boolean;
 	bambu_info: BambuInfo;
 	bambu_devices: Device[];
+	cloud?: BambuCloudConfig;
+	device_sync?: DeviceSyncSettings;
+	discovery?: DiscoverySettings;
+	media_download_dir?: string;
+	mjpeg_server?: MjpegServerConfig;
+	network?: NetworkSettings;
+	snapshot_settings?: SnapshotSettings;
+	timelapse_settings?: TimelapseSettings;
+};
+
+export type BambuCloudRegion = 'global' | 'china' | 'custom';
+
+export type BambuCloudConfig = {
+	region: BambuCloudRegion;
+	api_url?: string;
+	login_url?: string;
+	tfa_url?: string;
+	mqtt_url?: string;
+	mqtt_mode?: 'auto' | 'lan' | 'cloud';
+	request_policy?: BambuRequestPolicy;
+};
+
+export type BambuRequestPolicy = {
+	connect_timeout_secs: number;
+	request_timeout_secs: number;
+	max_retries: number;
+	initial_backoff_ms: number;
+	max_backoff_ms: number;
+	max_retry_after_secs: number;
+};
+
+export type BambuEndpoints = {
+	region: BambuCloudRegion;
+	api_url: string;
+	login_url: string;
+	tfa_url: string;
+	mqtt_url: string;
+};
+
+export type DeviceSyncSettings = {
+	enabled: boolean;
+	interval_minutes: number;
+};
+
+export type DiscoverySettings = {
+	continuous: boolean;
+	search_interval_minutes: number;
+	interfaces?: string[];
+};
+
+export type NetworkInterface = {
+	name: string;
+	ip: string;
+	is_loopback: boolean;
+	selected: boolean;
+};
+
+export type SnapshotSettings = {
+	enabled: boolean;
+	on_start: boolean;
+	on_finish: boolean;
+	on_hms: boolean;
+	progress_interval_percent: number;
+};
+
+export type TimelapseSettings = {
+	enabled: boolean;
+	fps: number;
+	keep_frames: boolean;
+};
+
+export type ProxySettings = {
+	url: string;
+	username?: string;
+	password?: string;
+	no_proxy?: string;
+};
+
+export type NetworkSettings = {
+	proxy?: ProxySettings | null;
+	extra_ca_certs?: string[];
+};
+
+export type MjpegServerConfig = {
+	enabled: boolean;
+	bind_address: string;
+	port: number;
 };
 
 export type BambuInfo = {
@@ -10,6 +97,15 @@ export type BambuInfo = {
 	refresh_token_expires_at: number;
 	jwt_expires_at: number;
 	jwt_last_refresh: number;
+	profile?: BambuAccountProfile | null;
+};
+
+export type BambuAccountProfile = {
+	uid: number;
+	username: string;
+	name: string;
+	avatar: string;
+	region: string;
 };
 
 export type BambuLoginResponse = {
@@ -17,6 +113,30 @@ export type BambuLoginResponse = {
 	refresh_token: string;
 };
 
+export type BambuApiErrorKind =
+	| 'network'
+	| 'unauthorized'
+	| 'invalid_credentials'
+	| 'forbidden'
+	| 'rate_limited'
+	| 'server'
+	| 'http'
+	| 'schema'
+	| 'cancelled'
+	| 'other';
+
+export type BambuApiError = {
+	kind: BambuApiErrorKind;
+	message: string;
+	status: number | null;
+	retry_after: number | null;
+	body: string | null;
+};
+
+export type BambuLoginStep =
+	| ({ status: 'complete' } & BambuLoginResponse)
+	| { status: 'code_required'; login_type: 'verifyCode' | 'tfa' };
+
 export type BambuDevicesResponse = {
 	message: string;
 	code?: string;
@@ -26,8 +146,23 @@ export type BambuDevicesResponse = {
 
 export type BambuDiscoveryResponse = {
 	devices: Device[];
+	announcements?: BambuAnnouncement[];
 }
 
+export type BambuAnnouncement = {
+	dev_id: string;
+	ip: string;
+	model: string;
+	name: string;
+	signal?: number | null;
+	connect: string;
+	lan_only: boolean;
+	bind: string;
+	bound: boolean;
+	se_mode: string;
+	version: string;
+};
+
 export type Device = {
 	dev_id: string;
 	name: string;
@@ -38,4 +173,212 @@ export type Device = {
 	dev_product_name: string;
 	dev_access_code: string;
 	nozzle_diameter: number;
+	removed?: boolean;
+};
+
+export type DeviceChangeKind =
+	| 'added'
+	| 'renamed'
+	| 'access_code_changed'
+	| 'model_changed'
+	| 'removed'
+	| 'restored';
+
+export type DeviceChange = {
+	dev_id: string;
+	name: string;
+	kind: DeviceChangeKind;
+	old_value?: string | null;
+	new_value?: string | null;
+};
+
+export type DevicesChangedEvent = {
+	changes: DeviceChange[];
+	devices: Device[];
+};
+
+export type ProjectFilament = {
+	id: number;
+	filament_type: string;
+	color: string;
+	used_g?: number;
+};
+
+export type AmsSlotAssignment = {
+	filament_id: number;
+	filament_type: string;
+	color: string;
+	satisfied: boolean;
+	tray: number | null;
+	tray_type: string | null;
+	tray_color: string | null;
+	tray_remain: number | null;
+	color_distance: number | null;
+	notes: string[];
+};
+
+export type AmsMappingSuggestion = {
+	ams_mapping: number[];
+	slots: AmsSlotAssignment[];
+};
+
+export type GcodePath = {
+	feature: number;
+	tool: number;
+	points: number[];
+};
+
+export type GcodeLayer = {
+	layer_num: number;
+	z: number;
+	height: number;
+	time_s: number;
+	extruded_mm: number;
+	paths: GcodePath[];
+	svg: string | null;
+};
+
+export type FilamentUsage = {
+	tool: number;
+	length_mm: number;
+	weight_g: number;
+};
+
+export type GcodeAnalysis = {
+	layer_count: number;
+	total_time_s: number;
+	preamble_time_s: number;
+	bounds: [number, number, number, number];
+	features: string[];
+	filament: FilamentUsage[];
+	layers: GcodeLayer[];
+};
+
+export type JobRecord = {
+	id: string;
+	dev_id: string;
+	task_id: string;
+	subtask_name: string;
+	gcode_file: string;
+	started_at: number;
+	finished_at: number | null;
+	status: 'running' | 'finish' | 'failed' | 'cancelled';
+};
+
+export type MediaFile = {
+	kind: 'timelapse' | 'recording';
+	name: string;
+	path: string;
+	size: number;
+	recorded_at: number | null;
+	job: JobRecord | null;
+};
+
+export type MediaListResponse = {
+	files: MediaFile[];
+};
+
+export type MediaDownloadProgress = {
+	download_id: number;
+	dev_id: string;
+	path: string;
+	local_path: string;
+	downloaded: number;
+	total: number;
+	done: boolean;
+	deleted: boolean;
+	error: string | null;
+};
+
+export type MediaDownloadFinished = {
+	download_id: number;
+	dev_id: string;
+	downloaded: string[];
+	failed: string[];
+	cancelled: boolean;
+};
+
+export type CameraFrameEvent = {
+	dev_id: string;
+	sequence: number;
+	captured_at: number;
+	size: number;
+};
+
+export type BambuTask = {
+	id: number;
+	title: string;
+	design_title: string;
+	device_id: string;
+	device_name: string;
+	device_model: string;
+	start_time: string;
+	end_time: string;
+	status: number;
+	weight: number;
+	length: number;
+	cost_time: number;
+	cover_url: string;
+	plate_index: number;
+	plate_name: string;
+};
+
+export type BambuTaskHistoryResponse = {
+	total: number;
+	tasks: BambuTask[];
+	cached: boolean;
+	cache_updated_at: number;
+};
+
+export type FirmwareModuleKind = 'ota' | 'ams' | 'mc' | 'toolhead' | 'camera' | 'other';
+
+export type FirmwareModuleVersion = {
+	name: string;
+	kind: FirmwareModuleKind;
+	sw_ver: string;
+	hw_ver: string;
+	sn: string;
+};
+
+export type AmsFirmwareEntry = {
+	name: string;
+	hardware: 'ams' | 'n3s' | 'n3f';
+	current_version: string;
+	latest_version: string | null;
+	update_available: boolean;
+};
+
+export type FirmwareReportEntry = {
+	dev_id: string;
+	name: string;
+	model: string;
+	modules: FirmwareModuleVersion[];
+	current_version?: string | null;
+	latest_version?: string | null;
+	update_available: boolean;
+	force_update: boolean;
+	ams: AmsFirmwareEntry[];
+	ams_update_available: boolean;
+	error?: string | null;
+};
+
+export type UpgradeState = {
+	status: string;
+	progress: number;
+	module?: string | null;
+	new_version?: string | null;
+	error_code: number;
+	message?: string | null;
+};
+
+export type UpgradeProgressEvent = {
+	dev_id: string;
+	name: string;
+	state: UpgradeState;
+};
+
+export type DeviceIpChange = {
+	device: Device;
+	old_ip?: string | null;
+	new_ip: string;
 };
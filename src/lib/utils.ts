@@ -7,3 +7,13 @@ export const awaiter = async <T>(promise: Promise<T>): Promise<[T | null, any |
 		return [null, err];
 	}
 };
+
+// Cloud commands reject with a BambuApiError object, everything else with a plain string
+// eslint-disable-next-line @typescript-eslint/no-explicit-any
+export const errorMessage = (err: any): string => {
+	if (err && typeof err === 'object' && 'message' in err) {
+		return err.message;
+	}
+
+	return err;
+};